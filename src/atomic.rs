@@ -0,0 +1,58 @@
+//! Output files are written under a temporary name next to the target and
+//! renamed into place only once complete, so an interrupted conversion never
+//! leaves a truncated file under the final name.
+
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+/// `dir/name.jp2` -> `dir/.name.jp2.tmp`: same directory (so the rename stays
+/// on one file system), hidden, and not picked up as an input.
+pub fn temp_path(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "output".to_string());
+    path.with_file_name(format!(".{}.tmp", name))
+}
+
+/// Runs `write` against the temporary path, flushes the result to disk and
+/// renames it over `path`. The temporary file is removed if any step fails.
+pub fn write_atomically(path: &Path, write: impl FnOnce(&Path) -> Result<()>) -> Result<()> {
+    let temp = temp_path(path);
+    let result = write(&temp).and_then(|()| commit(&temp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+fn commit(temp: &Path, path: &Path) -> Result<()> {
+    // Opened for writing: Windows cannot flush a read-only handle
+    OpenOptions::new()
+        .write(true)
+        .open(temp)
+        .and_then(|file| file.sync_all())
+        .with_context(|| format!("Syncing {}", temp.display()))?;
+    fs::rename(temp, path)
+        .with_context(|| format!("Renaming {} to {}", temp.display(), path.display()))?;
+    sync_parent(path);
+    Ok(())
+}
+
+/// Makes the rename itself durable. Best effort: some file systems refuse to
+/// sync a directory.
+#[cfg(unix)]
+fn sync_parent(path: &Path) {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    if let Ok(dir) = File::open(parent) {
+        let _ = dir.sync_all();
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) {}
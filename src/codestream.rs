@@ -0,0 +1,452 @@
+//! JPEG 2000 codestream marker analysis (ISO/IEC 15444-1 Annex A) for `--info`.
+//!
+//! Reads the main header and every tile-part header; packet data is skipped
+//! except for a short look at the first tile-part to confirm SOP/EPH use.
+
+use std::io::{Read, Seek, SeekFrom};
+
+use anyhow::{Context, Result, bail};
+
+const SOC: u16 = 0xFF4F;
+const SIZ: u16 = 0xFF51;
+const COD: u16 = 0xFF52;
+const COC: u16 = 0xFF53;
+const TLM: u16 = 0xFF55;
+const PLM: u16 = 0xFF57;
+const PLT: u16 = 0xFF58;
+const QCD: u16 = 0xFF5C;
+const QCC: u16 = 0xFF5D;
+const POC: u16 = 0xFF5F;
+const PPM: u16 = 0xFF60;
+const COM: u16 = 0xFF64;
+const SOT: u16 = 0xFF90;
+const SOP: u16 = 0xFF91;
+const EPH: u16 = 0xFF92;
+const SOD: u16 = 0xFF93;
+const EOC: u16 = 0xFFD9;
+
+/// Most tiles a codestream can have (Isot is 16 bits, 65535 is reserved).
+const MAX_TILES: u32 = 65535;
+
+/// Packet data scanned for an EPH marker in the first tile-part.
+const EPH_SCAN_LEN: u64 = 1 << 20;
+
+#[derive(Debug, Clone, Default)]
+pub struct CodestreamInfo {
+    /// Byte offset of SOC in the file.
+    pub offset: u64,
+    /// SIZ Rsiz (capabilities / profile).
+    pub rsiz: u16,
+    pub width: u32,
+    pub height: u32,
+    pub image_offset: (u32, u32),
+    pub tile_size: (u32, u32),
+    pub tile_offset: (u32, u32),
+    /// Tiles across and down the reference grid.
+    pub tiles: (u32, u32),
+    /// Per component: (bit depth, signed, XRsiz, YRsiz).
+    pub components: Vec<(u8, bool, u8, u8)>,
+    pub progression: u8,
+    pub layers: u16,
+    pub mct: u8,
+    /// Decomposition levels (resolutions = levels + 1).
+    pub levels: u8,
+    pub code_block: (u32, u32),
+    /// Code-block style byte (bit 0 = selective arithmetic coding bypass, ...).
+    pub code_block_style: u8,
+    /// Wavelet: 0 = 9/7 irreversible, 1 = 5/3 reversible.
+    pub transform: u8,
+    /// Precinct size per resolution level, lowest resolution first (empty = maximal).
+    pub precincts: Vec<(u32, u32)>,
+    /// COD Scod flags signalling SOP and EPH use.
+    pub sop_signalled: bool,
+    pub eph_signalled: bool,
+    /// SOP/EPH markers actually found in the first tile-part.
+    pub sop_found: bool,
+    pub eph_found: bool,
+    /// QCD quantization style (0 = none, 1 = scalar derived, 2 = scalar expounded) and guard bits.
+    pub quantization: u8,
+    pub guard_bits: u8,
+    pub tlm_segments: u32,
+    pub plm_segments: u32,
+    pub ppm_segments: u32,
+    pub plt_segments: u32,
+    pub coc_segments: u32,
+    pub qcc_segments: u32,
+    pub poc_segments: u32,
+    pub comments: Vec<String>,
+    pub tile_parts: u32,
+    /// Largest TNsot seen (tile-parts per tile as declared), 0 if never declared.
+    pub tile_parts_per_tile: u8,
+    /// Tiles that had at least one tile-part.
+    pub tiles_seen: u32,
+    /// Tile-part headers carrying COD/COC/QCD/QCC/POC overrides.
+    pub tile_header_overrides: u32,
+    pub eoc: bool,
+}
+
+impl CodestreamInfo {
+    pub fn tile_count(&self) -> u32 {
+        self.tiles.0.saturating_mul(self.tiles.1)
+    }
+}
+
+pub fn progression_name(order: u8) -> &'static str {
+    match order {
+        0 => "LRCP",
+        1 => "RLCP",
+        2 => "RPCL",
+        3 => "PCRL",
+        4 => "CPRL",
+        _ => "unknown",
+    }
+}
+
+/// Names of the code-block style flags that are set.
+pub fn code_block_style_names(style: u8) -> Vec<&'static str> {
+    [
+        (0x01, "BYPASS"),
+        (0x02, "RESET"),
+        (0x04, "TERMALL"),
+        (0x08, "VCAUSAL"),
+        (0x10, "PTERM"),
+        (0x20, "SEGSYM"),
+        (0x40, "HT"),
+    ]
+    .iter()
+    .filter(|(bit, _)| style & bit != 0)
+    .map(|(_, name)| *name)
+    .collect()
+}
+
+/// Analyses the codestream starting at byte `offset` of `file` and ending at `end`.
+pub fn read_codestream_info<R: Read + Seek>(
+    file: &mut R,
+    offset: u64,
+    end: u64,
+) -> Result<CodestreamInfo> {
+    let mut info = CodestreamInfo {
+        offset,
+        ..Default::default()
+    };
+
+    file.seek(SeekFrom::Start(offset))?;
+    if read_u16(file)? != SOC {
+        bail!("No SOC marker at offset {}", offset);
+    }
+
+    // Main header: marker segments up to the first SOT
+    let mut pos = offset + 2;
+    loop {
+        if pos + 2 > end {
+            bail!("Main header runs past the end of the codestream");
+        }
+        let marker = read_u16(file)?;
+        if marker == SOT || marker == EOC {
+            break;
+        }
+        let segment = read_segment(file)?;
+        match marker {
+            SIZ => parse_siz(&segment, &mut info)?,
+            COD => parse_cod(&segment, &mut info)?,
+            QCD => {
+                let sqcd = *segment.first().context("Empty QCD")?;
+                info.quantization = sqcd & 0x1F;
+                info.guard_bits = sqcd >> 5;
+            }
+            COC => info.coc_segments += 1,
+            QCC => info.qcc_segments += 1,
+            POC => info.poc_segments += 1,
+            TLM => info.tlm_segments += 1,
+            PLM => info.plm_segments += 1,
+            PPM => info.ppm_segments += 1,
+            // Rcom = 1: Latin-1 text
+            COM if segment.len() > 2 && segment[1] == 1 => info
+                .comments
+                .push(String::from_utf8_lossy(&segment[2..]).into_owned()),
+            _ => {}
+        }
+        pos += 2 + 2 + segment.len() as u64;
+    }
+
+    // Tile-parts: SOT segment, header markers up to SOD, then Psot bytes from SOT
+    let mut tiles_seen = vec![false; info.tile_count() as usize];
+    while pos + 2 <= end {
+        file.seek(SeekFrom::Start(pos))?;
+        let marker = read_u16(file)?;
+        if marker == EOC {
+            info.eoc = true;
+            break;
+        }
+        if marker != SOT {
+            bail!("Expected SOT at offset {}, found 0x{:04X}", pos, marker);
+        }
+        let sot = read_segment(file)?;
+        if sot.len() < 8 {
+            bail!("Truncated SOT at offset {}", pos);
+        }
+        let isot = u16::from_be_bytes([sot[0], sot[1]]) as usize;
+        let psot = u32::from_be_bytes([sot[2], sot[3], sot[4], sot[5]]) as u64;
+        info.tile_parts += 1;
+        info.tile_parts_per_tile = info.tile_parts_per_tile.max(sot[7]);
+        if let Some(seen) = tiles_seen.get_mut(isot) {
+            *seen = true;
+        }
+
+        let mut overridden = false;
+        loop {
+            let marker = read_u16(file)?;
+            if marker == SOD {
+                break;
+            }
+            read_segment(file)?;
+            match marker {
+                PLT => info.plt_segments += 1,
+                COD | COC | QCD | QCC | POC => overridden = true,
+                _ => {}
+            }
+        }
+        if overridden {
+            info.tile_header_overrides += 1;
+        }
+
+        if info.tile_parts == 1 {
+            let data_start = file.stream_position()?;
+            let data_end = if psot == 0 { end } else { pos + psot };
+            scan_packets(file, data_start, data_end, &mut info)?;
+        }
+
+        if psot == 0 {
+            // Last tile-part runs to EOC
+            break;
+        }
+        pos += psot;
+    }
+    info.tiles_seen = tiles_seen.iter().filter(|seen| **seen).count() as u32;
+    Ok(info)
+}
+
+/// Looks for SOP at the start of the packet data and EPH within the first bytes.
+fn scan_packets<R: Read + Seek>(
+    file: &mut R,
+    start: u64,
+    end: u64,
+    info: &mut CodestreamInfo,
+) -> Result<()> {
+    let len = end.saturating_sub(start).min(EPH_SCAN_LEN) as usize;
+    let mut data = vec![0u8; len];
+    file.seek(SeekFrom::Start(start))?;
+    file.read_exact(&mut data)?;
+    let sop = SOP.to_be_bytes();
+    let eph = EPH.to_be_bytes();
+    info.sop_found = data.starts_with(&sop);
+    info.eph_found = data.windows(2).any(|w| w == eph);
+    Ok(())
+}
+
+fn parse_siz(s: &[u8], info: &mut CodestreamInfo) -> Result<()> {
+    if s.len() < 36 {
+        bail!("Truncated SIZ");
+    }
+    let u32_at = |o: usize| u32::from_be_bytes([s[o], s[o + 1], s[o + 2], s[o + 3]]);
+    info.rsiz = u16::from_be_bytes([s[0], s[1]]);
+    let (xsiz, ysiz) = (u32_at(2), u32_at(6));
+    info.image_offset = (u32_at(10), u32_at(14));
+    info.tile_size = (u32_at(18), u32_at(22));
+    info.tile_offset = (u32_at(26), u32_at(30));
+    info.width = xsiz.saturating_sub(info.image_offset.0);
+    info.height = ysiz.saturating_sub(info.image_offset.1);
+    if info.tile_size.0 == 0 || info.tile_size.1 == 0 {
+        bail!("SIZ declares an empty tile size");
+    }
+    info.tiles = (
+        xsiz.saturating_sub(info.tile_offset.0)
+            .div_ceil(info.tile_size.0),
+        ysiz.saturating_sub(info.tile_offset.1)
+            .div_ceil(info.tile_size.1),
+    );
+    match info.tiles.0.checked_mul(info.tiles.1) {
+        Some(count) if count <= MAX_TILES => {}
+        _ => bail!(
+            "SIZ declares {}x{} tiles (at most {} allowed)",
+            info.tiles.0,
+            info.tiles.1,
+            MAX_TILES
+        ),
+    }
+    let csiz = u16::from_be_bytes([s[34], s[35]]) as usize;
+    info.components = s[36..]
+        .chunks_exact(3)
+        .take(csiz)
+        .map(|c| ((c[0] & 0x7F) + 1, c[0] & 0x80 != 0, c[1], c[2]))
+        .collect();
+    Ok(())
+}
+
+fn parse_cod(s: &[u8], info: &mut CodestreamInfo) -> Result<()> {
+    if s.len() < 10 {
+        bail!("Truncated COD");
+    }
+    let scod = s[0];
+    info.sop_signalled = scod & 0x02 != 0;
+    info.eph_signalled = scod & 0x04 != 0;
+    info.progression = s[1];
+    info.layers = u16::from_be_bytes([s[2], s[3]]);
+    info.mct = s[4];
+    info.levels = s[5];
+    // xcb and ycb are exponents offset by 2; at most 8 (code-blocks up to 1024 wide)
+    let (xcb, ycb) = (s[6], s[7]);
+    if xcb > 8 || ycb > 8 {
+        bail!(
+            "COD declares an invalid code-block size exponent ({}, {})",
+            xcb,
+            ycb
+        );
+    }
+    info.code_block = (1 << (xcb + 2), 1 << (ycb + 2));
+    info.code_block_style = s[8];
+    info.transform = s[9];
+    if scod & 0x01 != 0 {
+        // PPx in the low nibble, PPy in the high nibble; one byte per resolution
+        info.precincts = s[10..]
+            .iter()
+            .take(info.levels as usize + 1)
+            .map(|pp| (1 << (pp & 0x0F), 1 << (pp >> 4)))
+            .collect();
+    }
+    Ok(())
+}
+
+fn read_u16<R: Read>(file: &mut R) -> Result<u16> {
+    let mut b = [0u8; 2];
+    file.read_exact(&mut b)
+        .context("Unexpected end of codestream")?;
+    Ok(u16::from_be_bytes(b))
+}
+
+/// Reads a marker segment body (after the marker, Lxxx included in the count but not returned).
+fn read_segment<R: Read>(file: &mut R) -> Result<Vec<u8>> {
+    let len = read_u16(file)? as usize;
+    if len < 2 {
+        bail!("Invalid marker segment length {}", len);
+    }
+    let mut segment = vec![0u8; len - 2];
+    file.read_exact(&mut segment)
+        .context("Unexpected end of codestream")?;
+    Ok(segment)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn segment(marker: u16, body: &[u8]) -> Vec<u8> {
+        let mut out = marker.to_be_bytes().to_vec();
+        out.extend_from_slice(&((body.len() + 2) as u16).to_be_bytes());
+        out.extend_from_slice(body);
+        out
+    }
+
+    /// SIZ body: `size` image on `tile` tiles, three 8-bit components.
+    fn siz(size: (u32, u32), tile: (u32, u32)) -> Vec<u8> {
+        let mut s = 0u16.to_be_bytes().to_vec();
+        for v in [size.0, size.1, 0, 0, tile.0, tile.1, 0, 0] {
+            s.extend_from_slice(&v.to_be_bytes());
+        }
+        s.extend_from_slice(&3u16.to_be_bytes());
+        s.extend_from_slice(&[7, 1, 1].repeat(3));
+        s
+    }
+
+    /// RPCL, 1 layer, MCT, 2 levels, 64x64 code-blocks with BYPASS, 5/3,
+    /// SOP/EPH and precincts.
+    fn cod(xcb: u8) -> Vec<u8> {
+        vec![0x07, 2, 0, 1, 1, 2, xcb, 4, 0x01, 1, 0x77, 0x88, 0x88]
+    }
+
+    fn tile_part(isot: u16, packets: &[u8]) -> Vec<u8> {
+        let header = [segment(PLT, &[0, 4]), SOD.to_be_bytes().to_vec()].concat();
+        let psot = (12 + header.len() + packets.len()) as u32;
+        let mut sot = isot.to_be_bytes().to_vec();
+        sot.extend_from_slice(&psot.to_be_bytes());
+        sot.extend_from_slice(&[0, 1]);
+        [segment(SOT, &sot), header, packets.to_vec()].concat()
+    }
+
+    fn codestream(siz_body: &[u8], cod_body: &[u8]) -> Vec<u8> {
+        let packet = [0xFF, 0x91, 0, 4, 0, 0, 0x80, 0xFF, 0x92, 0x12];
+        [
+            SOC.to_be_bytes().to_vec(),
+            segment(SIZ, siz_body),
+            segment(COD, cod_body),
+            segment(QCD, &[0x40, 0x48, 0x50]),
+            segment(COM, &[0, 1, b'h', b'i']),
+            tile_part(0, &packet),
+            tile_part(1, &packet),
+            EOC.to_be_bytes().to_vec(),
+        ]
+        .concat()
+    }
+
+    fn analyse(bytes: &[u8]) -> Result<CodestreamInfo> {
+        read_codestream_info(&mut Cursor::new(bytes), 0, bytes.len() as u64)
+    }
+
+    #[test]
+    fn main_and_tile_part_headers() {
+        let info = analyse(&codestream(&siz((100, 50), (64, 64)), &cod(4))).unwrap();
+        assert_eq!((info.width, info.height), (100, 50));
+        assert_eq!(info.tiles, (2, 1));
+        assert_eq!(info.components, vec![(8, false, 1, 1); 3]);
+        assert_eq!(progression_name(info.progression), "RPCL");
+        assert_eq!((info.layers, info.mct, info.levels), (1, 1, 2));
+        assert_eq!(info.code_block, (64, 64));
+        assert_eq!(code_block_style_names(info.code_block_style), ["BYPASS"]);
+        assert_eq!(info.transform, 1);
+        assert_eq!(info.precincts, [(128, 128), (256, 256), (256, 256)]);
+        assert!(info.sop_signalled && info.eph_signalled);
+        assert!(info.sop_found && info.eph_found);
+        assert_eq!((info.quantization, info.guard_bits), (0, 2));
+        assert_eq!(info.comments, ["hi"]);
+        assert_eq!((info.tile_parts, info.tiles_seen), (2, 2));
+        assert_eq!((info.plt_segments, info.tile_parts_per_tile), (2, 1));
+        assert_eq!(info.tile_header_overrides, 0);
+        assert!(info.eoc);
+    }
+
+    #[test]
+    fn invalid_headers_are_errors() {
+        let ok_siz = siz((100, 50), (64, 64));
+        for (name, siz_body, cod_body) in [
+            ("xcb", ok_siz.clone(), cod(9)),
+            ("tiles", siz((100_000, 100_000), (1, 1)), cod(4)),
+            ("tile-overflow", siz((u32::MAX, u32::MAX), (1, 1)), cod(4)),
+            ("empty-tile", siz((100, 50), (0, 64)), cod(4)),
+            ("short-siz", ok_siz[..20].to_vec(), cod(4)),
+            ("short-cod", ok_siz.clone(), cod(4)[..6].to_vec()),
+        ] {
+            assert!(
+                analyse(&codestream(&siz_body, &cod_body)).is_err(),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn truncated_codestream_is_an_error_or_lacks_eoc() {
+        let full = codestream(&siz((100, 50), (64, 64)), &cod(4));
+        let main_header = full
+            .windows(2)
+            .position(|w| w == SOT.to_be_bytes())
+            .unwrap();
+        // A cut between tile-parts still reads, but must not claim an EOC
+        for cut in 0..full.len() - 2 {
+            if let Ok(info) = analyse(&full[..cut]) {
+                assert!(!info.eoc, "cut at {}", cut);
+                assert!(cut > main_header, "main header cut at {} accepted", cut);
+            }
+        }
+    }
+}
@@ -1,53 +1,503 @@
 use std::ffi::CString;
 use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
 use std::path::Path;
 
 use anyhow::{Context, Result, anyhow, bail};
+use clap::ValueEnum;
 use openjpeg_sys as opj;
-use tiff::encoder::{TiffEncoder, colortype};
-use tiff::tags::Tag;
+use tiff::Directory;
+use tiff::encoder::{Rational, TiffEncoder, TiffValue, colortype};
+use tiff::tags::{ExtraSamples, ResolutionUnit, Tag, Type};
 
 use crate::info::{Jp2ComponentInfo, Jp2Info};
+use crate::jp2box::BoxDetails;
+use crate::metadata::{self, EmbeddedMetadata};
+use crate::{atomic, codestream, jp2box, png, pnm, xmp};
 
-struct DecodedImage {
-    width: u32,
-    height: u32,
-    color: DecodedColor,
-    pixels: DecodedPixels,
-    icc_profile: Option<Vec<u8>>,
+#[derive(Clone, Copy, Debug)]
+pub struct DecodeOptions {
+    pub threads: i32,
+    /// Keep the codestream sample values as-is instead of rescaling to 8/16 bits.
+    pub native_precision: bool,
+    /// Discard the N highest resolution levels (each halves width and height).
+    pub reduce: u32,
+    /// Decode only this rectangle (full-resolution image coordinates).
+    pub region: Option<DecodeRegion>,
+    /// Decode only this tile (raster index in the tile grid).
+    pub tile: Option<u32>,
+    pub format: OutputFormat,
+}
+
+/// `--region x,y,w,h` in full-resolution pixels, relative to the image origin.
+#[derive(Clone, Copy, Debug)]
+pub struct DecodeRegion {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Output file format of `--decode`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Tiff,
+    Png,
+    /// PGM (grayscale) or PPM (RGB)
+    Pnm,
+}
+
+impl OutputFormat {
+    pub fn from_extension(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "tif" | "tiff" => Some(OutputFormat::Tiff),
+            "png" => Some(OutputFormat::Png),
+            "pnm" | "pgm" | "ppm" => Some(OutputFormat::Pnm),
+            _ => None,
+        }
+    }
+
+    /// Extension of derived output names.
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Tiff => "tif",
+            OutputFormat::Png => "png",
+            OutputFormat::Pnm => "pnm",
+        }
+    }
+}
+
+pub struct DecodedImage {
+    pub width: u32,
+    pub height: u32,
+    pub color: DecodedColor,
+    pub pixels: DecodedPixels,
+    pub icc_profile: Option<Vec<u8>>,
+    /// Per-channel significant bits when samples were written unscaled.
+    pub native_precision: Option<Vec<u32>>,
+    /// JP2 cdef channel type of the alpha component (1 = opacity, 2 = premultiplied).
+    pub alpha_type: Option<u16>,
+    /// Horizontal and vertical resolution in DPI from the JP2 'res ' box.
+    pub dpi: Option<(f64, f64)>,
+    /// XMP, EXIF, IPTC and 'xml ' boxes of the JP2, restored into TIFF tags.
+    pub metadata: EmbeddedMetadata,
 }
 
 #[derive(Clone, Copy)]
-enum DecodedColor {
+pub enum DecodedColor {
     Gray,
+    GrayAlpha,
     Rgb,
+    Rgba,
+    Cmyk,
+}
+
+impl DecodedColor {
+    pub fn channels(self) -> usize {
+        match self {
+            DecodedColor::Gray => 1,
+            DecodedColor::GrayAlpha => 2,
+            DecodedColor::Rgb => 3,
+            DecodedColor::Rgba | DecodedColor::Cmyk => 4,
+        }
+    }
 }
 
-enum DecodedPixels {
+pub enum DecodedPixels {
     U8(Vec<u8>),
     U16(Vec<u16>),
 }
 
+/// Original interleaved samples an encoded JP2 is checked against.
+pub struct ReferenceImage<'a> {
+    pub width: u32,
+    pub height: u32,
+    pub channels: u32,
+    pub samples: ReferenceSamples<'a>,
+}
+
+pub enum ReferenceSamples<'a> {
+    U8(&'a [u8]),
+    U16(&'a [u16]),
+}
+
 pub fn read_info(path: &Path, threads: i32) -> Result<Jp2Info> {
     let decoder = Decoder::open(path, threads, 0)?;
-    unsafe { info_from_image(decoder.image) }
+    let mut info = unsafe { info_from_image(decoder.image) }?;
+    info.layers = unsafe { default_layer_count(decoder.codec) };
+    let mut file = File::open(path).with_context(|| format!("Open {}", path.display()))?;
+    let file_len = file.metadata()?.len();
+    let codestream_range = if is_raw_codestream(path) {
+        Some((0, file_len))
+    } else {
+        info.boxes = jp2box::read_box_tree(&mut file, file_len)?;
+        info.boxes
+            .iter()
+            .find(|jp2_box| &jp2_box.kind == b"jp2c")
+            .map(|jp2c| {
+                let header = if jp2c.extended { 16 } else { 8 };
+                (jp2c.offset + header, jp2c.offset + jp2c.length)
+            })
+    };
+    if let Some((start, end)) = codestream_range {
+        // Marker problems are reported, but do not hide the rest of the header info
+        match codestream::read_codestream_info(&mut file, start, end) {
+            Ok(cs) => info.codestream = Some(cs),
+            Err(e) => eprintln!("WARN {}: codestream analysis failed: {}", path.display(), e),
+        }
+    }
+    Ok(info)
 }
 
-pub fn decode_to_tiff(input: &Path, output: &Path, threads: i32) -> Result<()> {
-    let image = decode_full(input, threads)?;
-    write_tiff(output, &image)
+/// Decodes `input` into `output`; the file appears only once fully written.
+pub fn decode_to_file(input: &Path, output: &Path, options: &DecodeOptions) -> Result<()> {
+    let image = decode_full(input, options)?;
+    atomic::write_atomically(output, |temp| match options.format {
+        OutputFormat::Tiff => write_tiff(temp, &image),
+        OutputFormat::Png => png::write_png(temp, &image),
+        // The flavour comes from the final name: the temporary one ends in .tmp
+        OutputFormat::Pnm => pnm::write_pnm(temp, &image, pnm::Flavour::from_path(output)),
+    })
 }
 
-fn decode_full(path: &Path, threads: i32) -> Result<DecodedImage> {
+/// Decodes `path` and compares the raw component samples with `reference`.
+/// Fails if geometry, component count or any single sample differs.
+pub fn verify_lossless(path: &Path, threads: i32, reference: &ReferenceImage<'_>) -> Result<()> {
     let decoder = Decoder::open(path, threads, 0)?;
+    decoder.decode()?;
+    unsafe { compare_with_reference(decoder.image, reference) }
+}
+
+/// Tile-at-a-time counterpart of `verify_lossless` for images that were
+/// encoded without ever being held in memory whole.
+pub struct TileVerifier {
+    decoder: Decoder,
+    data: Vec<u8>,
+}
+
+impl TileVerifier {
+    pub fn open(path: &Path, threads: i32) -> Result<Self> {
+        Ok(Self {
+            decoder: Decoder::open(path, threads, 0)?,
+            data: Vec::new(),
+        })
+    }
+
+    /// Decodes the next tile and compares it with `band`, which holds
+    /// full-width rows starting at image row `band_y0`.
+    pub fn verify_next(&mut self, band: &ReferenceImage<'_>, band_y0: u32) -> Result<()> {
+        let Some(tile) = (unsafe { self.decoder.read_tile_header() })? else {
+            bail!("Verification failed: codestream ended before the last tile");
+        };
+        if tile.components != band.channels {
+            bail!(
+                "Verification failed: {} component(s) decoded, expected {}",
+                tile.components,
+                band.channels
+            );
+        }
+        if tile.x1 > band.width || tile.y0 < band_y0 || tile.y1 > band_y0 + band.height {
+            bail!(
+                "Verification failed: tile {} covers x={}..{}, y={}..{}, outside rows {}..{}",
+                tile.index,
+                tile.x0,
+                tile.x1,
+                tile.y0,
+                tile.y1,
+                band_y0,
+                band_y0 + band.height
+            );
+        }
+
+        self.data.resize(tile.data_size as usize, 0);
+        let ok = unsafe {
+            opj::opj_decode_tile_data(
+                self.decoder.codec,
+                tile.index,
+                self.data.as_mut_ptr(),
+                tile.data_size,
+                self.decoder.stream,
+            )
+        } != 0;
+        if !ok {
+            bail!("opj_decode_tile_data failed (tile {})", tile.index);
+        }
+
+        let image_ref = unsafe { &*self.decoder.image };
+        let comps =
+            unsafe { std::slice::from_raw_parts(image_ref.comps, image_ref.numcomps as usize) };
+        let tile_w = (tile.x1 - tile.x0) as usize;
+        let tile_h = (tile.y1 - tile.y0) as usize;
+        let channels = band.channels as usize;
+        let row_len = band.width as usize * channels;
+        let mut mismatches = Mismatches::default();
+        let mut offset = 0usize;
+        for (c, component) in comps.iter().enumerate() {
+            if component.dx != 1 || component.dy != 1 {
+                bail!(
+                    "Verification failed: component {} is subsampled (dx={}, dy={})",
+                    c,
+                    component.dx,
+                    component.dy
+                );
+            }
+            // opj_decode_tile_data packs 1, 2 or 4 bytes per sample depending on precision
+            let size = match component.prec {
+                0..=8 => 1,
+                9..=16 => 2,
+                _ => 4,
+            };
+            let signed = component.sgnd != 0;
+            for y in 0..tile_h {
+                let row = (tile.y0 - band_y0) as usize + y;
+                for x in 0..tile_w {
+                    let bytes = &self.data[offset..offset + size];
+                    offset += size;
+                    let actual = match (size, signed) {
+                        (1, false) => bytes[0] as i32,
+                        (1, true) => bytes[0] as i8 as i32,
+                        (2, false) => u16::from_ne_bytes([bytes[0], bytes[1]]) as i32,
+                        (2, true) => i16::from_ne_bytes([bytes[0], bytes[1]]) as i32,
+                        _ => i32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+                    };
+                    let src = row * row_len + (tile.x0 as usize + x) * channels + c;
+                    let expected = match band.samples {
+                        ReferenceSamples::U8(samples) => samples[src] as i32,
+                        ReferenceSamples::U16(samples) => samples[src] as i32,
+                    };
+                    if actual != expected {
+                        let at = (tile.x0 as usize + x, band_y0 as usize + row);
+                        mismatches.record(at, c, expected, actual);
+                    }
+                }
+            }
+        }
+        mismatches.into_result()
+    }
+
+    /// Checks that no tiles are left over and ends decompression.
+    pub fn finish(self) -> Result<()> {
+        if let Some(tile) = (unsafe { self.decoder.read_tile_header() })? {
+            bail!("Verification failed: unexpected extra tile {}", tile.index);
+        }
+        let ended =
+            unsafe { opj::opj_end_decompress(self.decoder.codec, self.decoder.stream) } != 0;
+        if !ended {
+            bail!("opj_end_decompress failed");
+        }
+        Ok(())
+    }
+}
+
+struct TileHeader {
+    index: u32,
+    data_size: u32,
+    x0: u32,
+    y0: u32,
+    x1: u32,
+    y1: u32,
+    components: u32,
+}
+
+/// Counts differing samples and remembers the first one for the error message.
+#[derive(Default)]
+struct Mismatches {
+    count: u64,
+    first: Option<((usize, usize), usize, i32, i32)>,
+}
+
+impl Mismatches {
+    fn record(&mut self, at: (usize, usize), component: usize, expected: i32, actual: i32) {
+        self.count += 1;
+        if self.first.is_none() {
+            self.first = Some((at, component, expected, actual));
+        }
+    }
+
+    fn into_result(self) -> Result<()> {
+        if let Some(((x, y), c, expected, actual)) = self.first {
+            bail!(
+                "Verification failed: {} sample(s) differ; first at x={}, y={}, component {} (expected {}, decoded {})",
+                self.count,
+                x,
+                y,
+                c,
+                expected,
+                actual
+            );
+        }
+        Ok(())
+    }
+}
+
+fn decode_full(path: &Path, options: &DecodeOptions) -> Result<DecodedImage> {
+    // The reduction is applied after the header is read: with `cp_reduce` an
+    // excessive value only surfaces as a bare opj_read_header failure
+    let decoder = Decoder::open(path, options.threads, 0)?;
+    let (resolutions, tiles) = unsafe { decoder.layout() }?;
+    if options.reduce >= resolutions {
+        bail!(
+            "--reduce {} is too large: the codestream has {} resolution level(s), so at most --reduce {}",
+            options.reduce,
+            resolutions,
+            resolutions - 1
+        );
+    }
+    if options.reduce > 0 {
+        decoder.set_reduce(options.reduce)?;
+    }
+    match (options.tile, options.region) {
+        (Some(tile), _) => {
+            if tile >= tiles {
+                bail!(
+                    "--tile-index {} is out of range: the codestream has {} tile(s) (0..{})",
+                    tile,
+                    tiles,
+                    tiles - 1
+                );
+            }
+            decoder.decode_tile(tile)?;
+        }
+        (None, Some(region)) => {
+            decoder.set_decode_area(region)?;
+            decoder.decode()?;
+        }
+        (None, None) => decoder.decode()?,
+    }
+    // OpenJPEG skips 'colr' boxes with METH 3 (any ICC), so read those ourselves
+    let colr_icc = if is_raw_codestream(path) {
+        None
+    } else {
+        read_colr_icc(path)
+    };
+    let mut image = unsafe { image_to_pixels(decoder.image, options.native_precision, colr_icc) }?;
+    if !is_raw_codestream(path) {
+        // Each discarded resolution level halves the pixel count per inch
+        let scale = (1u32 << options.reduce) as f64;
+        image.dpi = read_dpi(path).map(|(h, v)| (h / scale, v / scale));
+        image.metadata = match metadata::read_jp2_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                eprintln!("WARN {}: metadata not restored: {:#}", path.display(), e);
+                Default::default()
+            }
+        };
+    }
+    Ok(image)
+}
 
-    let decoded = unsafe { opj::opj_decode(decoder.codec, decoder.stream, decoder.image) } != 0;
-    let ended = unsafe { opj::opj_end_decompress(decoder.codec, decoder.stream) } != 0;
-    if !decoded || !ended {
-        bail!("OpenJPEG FFI decompression failed");
+/// Expected peak memory of a decode: OpenJPEG's i32 component planes plus the
+/// interleaved output buffer, for the requested reduce/region/tile.
+pub fn estimate_decode_memory(path: &Path, options: &DecodeOptions) -> Result<u64> {
+    let decoder = Decoder::open(path, 1, 0)?;
+    let image = unsafe { &*decoder.image };
+    let components = image.numcomps as u64;
+    let (mut width, mut height) = ((image.x1 - image.x0) as u64, (image.y1 - image.y0) as u64);
+    if let Some(region) = options.region {
+        width = width.min(region.width as u64);
+        height = height.min(region.height as u64);
+    }
+    if options.tile.is_some() {
+        let (tile_w, tile_h) = unsafe { decoder.tile_size() }?;
+        width = width.min(tile_w as u64);
+        height = height.min(tile_h as u64);
     }
+    let pixels = (width >> options.reduce).max(1) * (height >> options.reduce).max(1);
+    Ok(pixels * components * (4 + 2))
+}
 
-    unsafe { image_to_pixels(decoder.image) }
+/// Resolution in DPI from the JP2 'res ' box, else from XMP `tiff:XResolution`.
+fn read_dpi(path: &Path) -> Option<(f64, f64)> {
+    let mut file = File::open(path).ok()?;
+    let file_len = file.metadata().ok()?.len();
+    let boxes = jp2box::read_box_tree(&mut file, file_len).ok()?;
+    res_box_dpi(&boxes).or_else(|| xmp::resolution_dpi(&jp2box::read_xmp(&mut file, &boxes)?))
+}
+
+/// Resolution from the JP2 'res ' box; capture ('resc') wins over display ('resd').
+fn res_box_dpi(boxes: &[jp2box::Jp2Box]) -> Option<(f64, f64)> {
+    let res = boxes
+        .iter()
+        .filter(|jp2_box| &jp2_box.kind == b"jp2h")
+        .flat_map(|jp2h| &jp2h.children)
+        .find(|jp2_box| &jp2_box.kind == b"res ")?;
+    [b"resc", b"resd"].iter().find_map(|kind| {
+        let jp2_box = res.children.iter().find(|b| &&b.kind == kind)?;
+        match jp2_box.details {
+            Some(BoxDetails::Resolution {
+                horizontal_dpi,
+                vertical_dpi,
+            }) if horizontal_dpi > 0.0
+                && vertical_dpi > 0.0
+                && horizontal_dpi.is_finite()
+                && vertical_dpi.is_finite() =>
+            {
+                Some((snap_dpi(horizontal_dpi), snap_dpi(vertical_dpi)))
+            }
+            _ => None,
+        }
+    })
+}
+
+/// Whole pixels per metre cannot hold e.g. 400 DPI exactly (15748 ppm = 399.9992),
+/// so values within half a ppm of a whole DPI are snapped to it.
+fn snap_dpi(dpi: f64) -> f64 {
+    if (dpi - dpi.round()).abs() <= 0.5 * 0.0254 {
+        dpi.round()
+    } else {
+        dpi
+    }
+}
+
+/// ICC profile from the first 'colr' box with METH 2 or 3 in the JP2 header.
+fn read_colr_icc(path: &Path) -> Option<Vec<u8>> {
+    let mut file = File::open(path).ok()?;
+    let jp2h = read_top_level_box(&mut file, b"jp2h")?;
+    let mut off = 0usize;
+    while off + 8 <= jp2h.len() {
+        let len = u32::from_be_bytes(jp2h[off..off + 4].try_into().ok()?) as usize;
+        if len < 8 || off + len > jp2h.len() {
+            return None;
+        }
+        let payload = &jp2h[off + 8..off + len];
+        if &jp2h[off + 4..off + 8] == b"colr" && payload.len() > 3 && matches!(payload[0], 2 | 3) {
+            return Some(payload[3..].to_vec());
+        }
+        off += len;
+    }
+    None
+}
+
+/// Payload of the first top-level box of type `kind`, skipping others by seeking.
+fn read_top_level_box(file: &mut File, kind: &[u8; 4]) -> Option<Vec<u8>> {
+    let file_len = file.metadata().ok()?.len();
+    let mut pos = 0u64;
+    while pos + 8 <= file_len {
+        file.seek(SeekFrom::Start(pos)).ok()?;
+        let mut header = [0u8; 8];
+        file.read_exact(&mut header).ok()?;
+        let lbox = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as u64;
+        let (header_len, box_len) = match lbox {
+            0 => (8, file_len - pos),
+            1 => {
+                let mut xl = [0u8; 8];
+                file.read_exact(&mut xl).ok()?;
+                (16, u64::from_be_bytes(xl))
+            }
+            n => (8, n),
+        };
+        if box_len < header_len || pos + box_len > file_len {
+            return None;
+        }
+        if &header[4..8] == kind {
+            let mut payload = vec![0u8; (box_len - header_len) as usize];
+            file.read_exact(&mut payload).ok()?;
+            return Some(payload);
+        }
+        pos += box_len;
+    }
+    None
 }
 
 struct Decoder {
@@ -116,6 +566,136 @@ impl Decoder {
             image,
         })
     }
+
+    fn decode(&self) -> Result<()> {
+        let decoded = unsafe { opj::opj_decode(self.codec, self.stream, self.image) } != 0;
+        let ended = unsafe { opj::opj_end_decompress(self.codec, self.stream) } != 0;
+        if !decoded || !ended {
+            bail!("OpenJPEG FFI decompression failed");
+        }
+        Ok(())
+    }
+
+    /// Resolution levels (default COD) and number of tiles from the main header.
+    unsafe fn layout(&self) -> Result<(u32, u32)> {
+        let mut cstr = unsafe { opj::opj_get_cstr_info(self.codec) };
+        if cstr.is_null() {
+            bail!("opj_get_cstr_info failed");
+        }
+        let info = unsafe { &*cstr };
+        let tccp = info.m_default_tile_info.tccp_info;
+        let resolutions = if tccp.is_null() {
+            1
+        } else {
+            unsafe { (*tccp).numresolutions }
+        };
+        let tiles = info.tw * info.th;
+        unsafe { opj::opj_destroy_cstr_info(&mut cstr) };
+        Ok((resolutions, tiles))
+    }
+
+    /// Nominal tile width and height from SIZ.
+    unsafe fn tile_size(&self) -> Result<(u32, u32)> {
+        let mut cstr = unsafe { opj::opj_get_cstr_info(self.codec) };
+        if cstr.is_null() {
+            bail!("opj_get_cstr_info failed");
+        }
+        let size = unsafe { ((*cstr).tdx, (*cstr).tdy) };
+        unsafe { opj::opj_destroy_cstr_info(&mut cstr) };
+        Ok(size)
+    }
+
+    fn set_reduce(&self, reduce: u32) -> Result<()> {
+        let ok = unsafe { opj::opj_set_decoded_resolution_factor(self.codec, reduce) } != 0;
+        if !ok {
+            bail!("opj_set_decoded_resolution_factor failed");
+        }
+        Ok(())
+    }
+
+    /// Restricts decoding to `region`, which must lie inside the image.
+    fn set_decode_area(&self, region: DecodeRegion) -> Result<()> {
+        let image = unsafe { &*self.image };
+        let (width, height) = (image.x1 - image.x0, image.y1 - image.y0);
+        let x1 = region.x.checked_add(region.width);
+        let y1 = region.y.checked_add(region.height);
+        if region.width == 0
+            || region.height == 0
+            || x1.is_none_or(|x1| x1 > width)
+            || y1.is_none_or(|y1| y1 > height)
+        {
+            bail!(
+                "--region {},{},{},{} is outside the {}x{} image",
+                region.x,
+                region.y,
+                region.width,
+                region.height,
+                width,
+                height
+            );
+        }
+        let coord = |v: u32| i32::try_from(v).context("--region coordinate is too large");
+        let ok = unsafe {
+            opj::opj_set_decode_area(
+                self.codec,
+                self.image,
+                coord(image.x0 + region.x)?,
+                coord(image.y0 + region.y)?,
+                coord(image.x0 + region.x + region.width)?,
+                coord(image.y0 + region.y + region.height)?,
+            )
+        } != 0;
+        if !ok {
+            bail!("opj_set_decode_area failed");
+        }
+        Ok(())
+    }
+
+    /// Decodes a single tile into the image (its extent becomes the tile's).
+    fn decode_tile(&self, index: u32) -> Result<()> {
+        let ok =
+            unsafe { opj::opj_get_decoded_tile(self.codec, self.stream, self.image, index) } != 0;
+        if !ok {
+            bail!("OpenJPEG could not decode tile {}", index);
+        }
+        Ok(())
+    }
+
+    /// Reads the next tile-part header; `None` once the codestream is exhausted.
+    unsafe fn read_tile_header(&self) -> Result<Option<TileHeader>> {
+        let (mut index, mut data_size, mut components) = (0u32, 0u32, 0u32);
+        let (mut x0, mut y0, mut x1, mut y1) = (0i32, 0i32, 0i32, 0i32);
+        let mut go_on: opj::OPJ_BOOL = 0;
+        let ok = unsafe {
+            opj::opj_read_tile_header(
+                self.codec,
+                self.stream,
+                &mut index,
+                &mut data_size,
+                &mut x0,
+                &mut y0,
+                &mut x1,
+                &mut y1,
+                &mut components,
+                &mut go_on,
+            )
+        } != 0;
+        if !ok {
+            bail!("opj_read_tile_header failed");
+        }
+        if go_on == 0 {
+            return Ok(None);
+        }
+        Ok(Some(TileHeader {
+            index,
+            data_size,
+            x0: x0.max(0) as u32,
+            y0: y0.max(0) as u32,
+            x1: x1.max(0) as u32,
+            y1: y1.max(0) as u32,
+            components,
+        }))
+    }
 }
 
 impl Drop for Decoder {
@@ -160,10 +740,100 @@ unsafe fn info_from_image(image: *mut opj::opj_image_t) -> Result<Jp2Info> {
         height: image_ref.y1.saturating_sub(image_ref.y0),
         components,
         icc_profile_len: image_ref.icc_profile_len,
+        layers: 0,
+        boxes: Vec::new(),
+        codestream: None,
     })
 }
 
-unsafe fn image_to_pixels(image: *mut opj::opj_image_t) -> Result<DecodedImage> {
+/// Number of quality layers from the main header COD (0 if OpenJPEG cannot tell).
+unsafe fn default_layer_count(codec: *mut opj::opj_codec_t) -> u32 {
+    let mut cstr = unsafe { opj::opj_get_cstr_info(codec) };
+    if cstr.is_null() {
+        return 0;
+    }
+    let layers = unsafe { (*cstr).m_default_tile_info.numlayers };
+    unsafe { opj::opj_destroy_cstr_info(&mut cstr) };
+    layers
+}
+
+unsafe fn compare_with_reference(
+    image: *mut opj::opj_image_t,
+    reference: &ReferenceImage<'_>,
+) -> Result<()> {
+    if image.is_null() {
+        bail!("OpenJPEG returned a null image");
+    }
+    let image_ref = unsafe { &*image };
+    if image_ref.numcomps != reference.channels {
+        bail!(
+            "Verification failed: {} component(s) decoded, expected {}",
+            image_ref.numcomps,
+            reference.channels
+        );
+    }
+    if image_ref.comps.is_null() {
+        bail!("OpenJPEG returned an image without components");
+    }
+
+    let comps = unsafe { std::slice::from_raw_parts(image_ref.comps, image_ref.numcomps as usize) };
+    let width = reference.width as usize;
+    let height = reference.height as usize;
+    let channels = reference.channels as usize;
+    let expected_len = width * height * channels;
+    let reference_len = match reference.samples {
+        ReferenceSamples::U8(samples) => samples.len(),
+        ReferenceSamples::U16(samples) => samples.len(),
+    };
+    if reference_len < expected_len {
+        bail!(
+            "Verification failed: reference buffer holds {} samples, expected {}",
+            reference_len,
+            expected_len
+        );
+    }
+
+    let mut mismatches = Mismatches::default();
+    for (c, component) in comps.iter().enumerate() {
+        if component.w != reference.width
+            || component.h != reference.height
+            || component.dx != 1
+            || component.dy != 1
+        {
+            bail!(
+                "Verification failed: component {} is {}x{} (dx={}, dy={}), expected {}x{}",
+                c,
+                component.w,
+                component.h,
+                component.dx,
+                component.dy,
+                reference.width,
+                reference.height
+            );
+        }
+        if component.data.is_null() {
+            bail!("OpenJPEG returned a component without sample data");
+        }
+        let plane = unsafe { std::slice::from_raw_parts(component.data, width * height) };
+        for (i, &actual) in plane.iter().enumerate() {
+            let src = i * channels + c;
+            let expected = match reference.samples {
+                ReferenceSamples::U8(samples) => samples[src] as i32,
+                ReferenceSamples::U16(samples) => samples[src] as i32,
+            };
+            if actual != expected {
+                mismatches.record((i % width, i / width), c, expected, actual);
+            }
+        }
+    }
+    mismatches.into_result()
+}
+
+unsafe fn image_to_pixels(
+    image: *mut opj::opj_image_t,
+    native: bool,
+    colr_icc: Option<Vec<u8>>,
+) -> Result<DecodedImage> {
     if image.is_null() {
         bail!("OpenJPEG returned a null image");
     }
@@ -179,39 +849,83 @@ unsafe fn image_to_pixels(image: *mut opj::opj_image_t) -> Result<DecodedImage>
         bail!("OpenJPEG returned empty image components");
     }
 
+    // Alpha is only recognised when the cdef box flagged the component (OpenJPEG sets `alpha`).
+    let is_alpha = |idx: usize| comps.get(idx).is_some_and(|component| component.alpha != 0);
     let component_count = comps.len();
-    let color = if component_count >= 3 {
-        DecodedColor::Rgb
+    let icc = if !image_ref.icc_profile_buf.is_null() && image_ref.icc_profile_len > 0 {
+        Some(unsafe {
+            std::slice::from_raw_parts(
+                image_ref.icc_profile_buf,
+                image_ref.icc_profile_len as usize,
+            )
+        })
+    } else {
+        colr_icc.as_deref()
+    };
+    // CMYK is signalled either by enumcs 12 or by the embedded ICC profile's colour space.
+    let is_cmyk = matches!(image_ref.color_space, opj::COLOR_SPACE::OPJ_CLRSPC_CMYK)
+        || icc.is_some_and(|icc| icc.get(16..20) == Some(b"CMYK".as_slice()));
+    let color = if component_count >= 4 && is_cmyk {
+        DecodedColor::Cmyk
+    } else if component_count >= 3 {
+        if is_alpha(3) {
+            DecodedColor::Rgba
+        } else {
+            DecodedColor::Rgb
+        }
+    } else if is_alpha(1) {
+        DecodedColor::GrayAlpha
     } else {
         DecodedColor::Gray
     };
+    let channels = color.channels();
+    let alpha_type = match color {
+        DecodedColor::GrayAlpha => Some(comps[1].alpha),
+        DecodedColor::Rgba => Some(comps[3].alpha),
+        DecodedColor::Gray | DecodedColor::Rgb | DecodedColor::Cmyk => None,
+    };
     let precision = comps
         .iter()
-        .take(if matches!(color, DecodedColor::Rgb) {
-            3
-        } else {
-            1
-        })
+        .take(channels)
         .map(|component| component.prec)
         .max()
         .unwrap_or(8);
 
-    let icc_profile = if !image_ref.icc_profile_buf.is_null() && image_ref.icc_profile_len > 0 {
-        let bytes = unsafe {
-            std::slice::from_raw_parts(
-                image_ref.icc_profile_buf,
-                image_ref.icc_profile_len as usize,
-            )
-        };
-        Some(bytes.to_vec())
+    let native_precision = if native {
+        if precision > 16 {
+            bail!(
+                "Native precision decode supports at most 16 bits per sample (got {})",
+                precision
+            );
+        }
+        if comps
+            .iter()
+            .take(channels)
+            .any(|component| component.sgnd != 0)
+        {
+            bail!("Native precision decode does not support signed components");
+        }
+        Some(
+            comps
+                .iter()
+                .take(channels)
+                .map(|component| component.prec)
+                .collect(),
+        )
     } else {
         None
     };
 
+    let icc_profile = icc.map(|bytes| bytes.to_vec());
+
     let pixels = if precision <= 8 {
-        DecodedPixels::U8(image_to_interleaved_u8(comps, width, height, color)?)
+        DecodedPixels::U8(image_to_interleaved_u8(
+            comps, width, height, color, native,
+        )?)
     } else {
-        DecodedPixels::U16(image_to_interleaved_u16(comps, width, height, color)?)
+        DecodedPixels::U16(image_to_interleaved_u16(
+            comps, width, height, color, native,
+        )?)
     };
 
     Ok(DecodedImage {
@@ -220,6 +934,10 @@ unsafe fn image_to_pixels(image: *mut opj::opj_image_t) -> Result<DecodedImage>
         color,
         pixels,
         icc_profile,
+        native_precision,
+        alpha_type,
+        dpi: None,
+        metadata: EmbeddedMetadata::default(),
     })
 }
 
@@ -228,22 +946,20 @@ fn image_to_interleaved_u8(
     width: u32,
     height: u32,
     color: DecodedColor,
+    native: bool,
 ) -> Result<Vec<u8>> {
-    let channels = if matches!(color, DecodedColor::Rgb) {
-        3
+    let sample = if native {
+        component_sample_native::<u8>
     } else {
-        1
+        component_sample_to_u8
     };
+    let channels = color.channels();
     let mut out = vec![0u8; width as usize * height as usize * channels];
     for y in 0..height as usize {
         for x in 0..width as usize {
             let dst = (y * width as usize + x) * channels;
-            if matches!(color, DecodedColor::Rgb) {
-                out[dst] = component_sample_to_u8(&comps[0], x, y, width, height)?;
-                out[dst + 1] = component_sample_to_u8(&comps[1], x, y, width, height)?;
-                out[dst + 2] = component_sample_to_u8(&comps[2], x, y, width, height)?;
-            } else {
-                out[dst] = component_sample_to_u8(&comps[0], x, y, width, height)?;
+            for (c, component) in comps.iter().take(channels).enumerate() {
+                out[dst + c] = sample(component, x, y, width, height)?;
             }
         }
     }
@@ -255,22 +971,20 @@ fn image_to_interleaved_u16(
     width: u32,
     height: u32,
     color: DecodedColor,
+    native: bool,
 ) -> Result<Vec<u16>> {
-    let channels = if matches!(color, DecodedColor::Rgb) {
-        3
+    let sample = if native {
+        component_sample_native::<u16>
     } else {
-        1
+        component_sample_to_u16
     };
+    let channels = color.channels();
     let mut out = vec![0u16; width as usize * height as usize * channels];
     for y in 0..height as usize {
         for x in 0..width as usize {
             let dst = (y * width as usize + x) * channels;
-            if matches!(color, DecodedColor::Rgb) {
-                out[dst] = component_sample_to_u16(&comps[0], x, y, width, height)?;
-                out[dst + 1] = component_sample_to_u16(&comps[1], x, y, width, height)?;
-                out[dst + 2] = component_sample_to_u16(&comps[2], x, y, width, height)?;
-            } else {
-                out[dst] = component_sample_to_u16(&comps[0], x, y, width, height)?;
+            for (c, component) in comps.iter().take(channels).enumerate() {
+                out[dst + c] = sample(component, x, y, width, height)?;
             }
         }
     }
@@ -309,6 +1023,20 @@ fn component_sample_to_u16(
     }
 }
 
+/// Returns the sample unscaled, clamped to the component's own precision.
+fn component_sample_native<T: TryFrom<u64>>(
+    component: &opj::opj_image_comp_t,
+    x: usize,
+    y: usize,
+    out_width: u32,
+    out_height: u32,
+) -> Result<T> {
+    let sample = component_sample(component, x, y, out_width, out_height)?;
+    let precision = component.prec.clamp(1, 31);
+    let value = normalize_sample(sample, precision, false);
+    T::try_from(value).map_err(|_| anyhow!("sample {} does not fit the TIFF container", value))
+}
+
 fn component_sample(
     component: &opj::opj_image_comp_t,
     x: usize,
@@ -343,31 +1071,81 @@ fn normalize_sample(sample: i32, precision: u32, signed: bool) -> u64 {
 fn write_tiff(path: &Path, image: &DecodedImage) -> Result<()> {
     let file = File::create(path).with_context(|| format!("creating {}", path.display()))?;
     let mut encoder = TiffEncoder::new(file)?;
+    let exif = write_exif_directory(&mut encoder, path, image)?;
+    let tags = ImageTags { image, exif };
     match (&image.color, &image.pixels) {
-        (DecodedColor::Gray, DecodedPixels::U8(pixels)) => {
-            let mut tiff = encoder.new_image::<colortype::Gray8>(image.width, image.height)?;
-            write_icc_tag(&mut tiff, image.icc_profile.as_deref())?;
-            tiff.write_data(pixels)?;
+        (DecodedColor::Gray | DecodedColor::GrayAlpha, DecodedPixels::U8(pixels)) => {
+            write_tiff_image::<_, colortype::Gray8>(&mut encoder, &tags, pixels)
+        }
+        (DecodedColor::Gray | DecodedColor::GrayAlpha, DecodedPixels::U16(pixels)) => {
+            write_tiff_image::<_, colortype::Gray16>(&mut encoder, &tags, pixels)
         }
-        (DecodedColor::Gray, DecodedPixels::U16(pixels)) => {
-            let mut tiff = encoder.new_image::<colortype::Gray16>(image.width, image.height)?;
-            write_icc_tag(&mut tiff, image.icc_profile.as_deref())?;
-            tiff.write_data(pixels)?;
+        (DecodedColor::Rgb | DecodedColor::Rgba, DecodedPixels::U8(pixels)) => {
+            write_tiff_image::<_, colortype::RGB8>(&mut encoder, &tags, pixels)
         }
-        (DecodedColor::Rgb, DecodedPixels::U8(pixels)) => {
-            let mut tiff = encoder.new_image::<colortype::RGB8>(image.width, image.height)?;
-            write_icc_tag(&mut tiff, image.icc_profile.as_deref())?;
-            tiff.write_data(pixels)?;
+        (DecodedColor::Rgb | DecodedColor::Rgba, DecodedPixels::U16(pixels)) => {
+            write_tiff_image::<_, colortype::RGB16>(&mut encoder, &tags, pixels)
         }
-        (DecodedColor::Rgb, DecodedPixels::U16(pixels)) => {
-            let mut tiff = encoder.new_image::<colortype::RGB16>(image.width, image.height)?;
-            write_icc_tag(&mut tiff, image.icc_profile.as_deref())?;
-            tiff.write_data(pixels)?;
+        (DecodedColor::Cmyk, DecodedPixels::U8(pixels)) => {
+            write_tiff_image::<_, colortype::CMYK8>(&mut encoder, &tags, pixels)
+        }
+        (DecodedColor::Cmyk, DecodedPixels::U16(pixels)) => {
+            write_tiff_image::<_, colortype::CMYK16>(&mut encoder, &tags, pixels)
         }
     }
+}
+
+/// What goes into the image IFD besides the pixels.
+struct ImageTags<'a> {
+    image: &'a DecodedImage,
+    /// Offset of the already written EXIF IFD.
+    exif: Option<u32>,
+}
+
+/// Writes one IFD; an alpha component becomes an ExtraSamples entry on top of `C`.
+fn write_tiff_image<W: std::io::Write + std::io::Seek, C: colortype::ColorType>(
+    encoder: &mut TiffEncoder<W>,
+    tags: &ImageTags,
+    pixels: &[C::Inner],
+) -> Result<()>
+where
+    [C::Inner]: TiffValue,
+{
+    let image = tags.image;
+    let mut tiff = encoder.new_image::<C>(image.width, image.height)?;
+    if let Some(alpha_type) = image.alpha_type {
+        let extra = match alpha_type {
+            1 => ExtraSamples::UnassociatedAlpha,
+            2 => ExtraSamples::AssociatedAlpha,
+            _ => ExtraSamples::Unspecified,
+        };
+        tiff.extra_samples(&[extra])?;
+    }
+    write_icc_tag(&mut tiff, image.icc_profile.as_deref())?;
+    if let Some((horizontal, vertical)) = image.dpi {
+        tiff.resolution_unit(ResolutionUnit::Inch);
+        tiff.x_resolution(dpi_rational(horizontal));
+        tiff.y_resolution(dpi_rational(vertical));
+    }
+    write_precision_tag(&mut tiff, image.native_precision.as_deref())?;
+    write_metadata_tags(&mut tiff, &image.metadata, tags.exif)?;
+    tiff.write_data(pixels)?;
     Ok(())
 }
 
+/// DPI as a TIFF rational with 1/1000 precision, reduced (400.0 -> 400/1).
+fn dpi_rational(dpi: f64) -> Rational {
+    let mut n = (dpi * 1000.0).round().clamp(1.0, u32::MAX as f64) as u32;
+    let mut d = 1000u32;
+    let (mut a, mut b) = (n, d);
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    n /= a;
+    d /= a;
+    Rational { n, d }
+}
+
 fn write_icc_tag<
     'a,
     W: std::io::Write + std::io::Seek,
@@ -386,6 +1164,104 @@ fn write_icc_tag<
     Ok(())
 }
 
+/// Writes the EXIF entries of the JP2 as a TIFF EXIF IFD (before the image
+/// IFD that points to it). A block that cannot be parsed is skipped with a WARN.
+fn write_exif_directory<W: std::io::Write + std::io::Seek>(
+    encoder: &mut TiffEncoder<W>,
+    path: &Path,
+    image: &DecodedImage,
+) -> Result<Option<u32>> {
+    let Some(stream) = &image.metadata.exif else {
+        return Ok(None);
+    };
+    let entries = match metadata::parse_exif_stream(stream) {
+        Ok(entries) if !entries.is_empty() => entries,
+        Ok(_) => return Ok(None),
+        Err(e) => {
+            eprintln!("WARN {}: EXIF box not restored: {:#}", path.display(), e);
+            return Ok(None);
+        }
+    };
+    let mut exif = encoder.extra_directory()?;
+    let mut directory = Directory::empty();
+    for entry in entries {
+        let Some(kind) = Type::from_u16(entry.kind) else {
+            continue;
+        };
+        let written = exif
+            .write_entry_bytes(kind, &entry.value)
+            .with_context(|| format!("writing EXIF tag {}", entry.tag))?;
+        directory.extend([(Tag::from_u16_exhaustive(entry.tag), written)]);
+    }
+    exif.extend_from(&directory);
+    let offset = exif.finish_with_offsets()?;
+    Ok(Some(offset.offset))
+}
+
+/// XMP (700), IPTC (33723), ImageDescription from the 'xml ' box and the
+/// pointer to the EXIF IFD.
+fn write_metadata_tags<
+    'a,
+    W: std::io::Write + std::io::Seek,
+    C: tiff::encoder::colortype::ColorType,
+    K: tiff::encoder::TiffKind,
+>(
+    image: &mut tiff::encoder::ImageEncoder<'a, W, C, K>,
+    metadata: &EmbeddedMetadata,
+    exif: Option<u32>,
+) -> Result<()> {
+    let encoder = image.encoder();
+    if let Some(xmp) = metadata.xmp.as_deref().filter(|xmp| !xmp.is_empty()) {
+        encoder
+            .write_tag(Tag::Unknown(700), xmp)
+            .context("writing TIFF XMP tag")?;
+    }
+    if let Some(iptc) = metadata.iptc.as_deref().filter(|iptc| !iptc.is_empty()) {
+        let entry = encoder.write_entry_bytes(Type::UNDEFINED, iptc)?;
+        let mut directory = Directory::empty();
+        directory.extend([(Tag::Unknown(33723), entry)]);
+        encoder.extend_from(&directory);
+    }
+    if let Some(xml) = &metadata.xml {
+        // ASCII values end at the first NUL
+        let text = xml.split('\0').next().unwrap_or_default().trim();
+        if !text.is_empty() {
+            encoder
+                .write_tag(Tag::ImageDescription, text)
+                .context("writing TIFF ImageDescription")?;
+        }
+    }
+    if let Some(offset) = exif {
+        encoder
+            .write_tag(Tag::ExifDirectory, offset)
+            .context("writing TIFF EXIF IFD pointer")?;
+    }
+    Ok(())
+}
+
+/// Records the true sample precision via MaxSampleValue (tag 281) for native decodes.
+fn write_precision_tag<
+    'a,
+    W: std::io::Write + std::io::Seek,
+    C: tiff::encoder::colortype::ColorType,
+    K: tiff::encoder::TiffKind,
+>(
+    image: &mut tiff::encoder::ImageEncoder<'a, W, C, K>,
+    precision: Option<&[u32]>,
+) -> Result<()> {
+    if let Some(precision) = precision {
+        let max_values: Vec<u16> = precision
+            .iter()
+            .map(|&bits| ((1u32 << bits.clamp(1, 16)) - 1) as u16)
+            .collect();
+        image
+            .encoder()
+            .write_tag(Tag::MaxSampleValue, &max_values[..])
+            .context("writing TIFF MaxSampleValue tag")?;
+    }
+    Ok(())
+}
+
 fn path_to_cstring(path: &Path) -> Result<CString> {
     CString::new(path.to_string_lossy().as_bytes())
         .with_context(|| format!("path contains NUL byte: {}", path.display()))
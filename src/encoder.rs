@@ -1,7 +1,7 @@
 use std::{
     ffi::CString,
     fs::{self, File},
-    io::{Read, Write},
+    io::{BufReader, Read, Seek, Write},
     path::{Path, PathBuf},
 };
 
@@ -11,17 +11,24 @@ use openjpeg_sys::opj_encoder_set_extra_options;
 use openjpeg_sys::{
     CODEC_FORMAT, COLOR_SPACE, PROG_ORDER, opj_codec_set_threads, opj_codec_t, opj_cparameters_t,
     opj_create_compress, opj_destroy_codec, opj_encode, opj_end_compress, opj_image_cmptparm_t,
-    opj_image_create, opj_image_destroy, opj_image_t, opj_set_default_encoder_parameters,
-    opj_setup_encoder, opj_start_compress, opj_stream_create_default_file_stream,
-    opj_stream_destroy, opj_stream_t,
+    opj_image_create, opj_image_destroy, opj_image_t, opj_image_tile_create,
+    opj_set_default_encoder_parameters, opj_setup_encoder, opj_start_compress,
+    opj_stream_create_default_file_stream, opj_stream_destroy, opj_stream_t, opj_write_tile,
 };
 use rayon::prelude::*;
 use std::ffi::c_char;
 use tiff::ColorType;
 use tiff::decoder::ifd::Value;
-use tiff::decoder::{Decoder, DecodingResult};
+use tiff::decoder::{Decoder, DecodingResult, Limits};
 use tiff::tags::Tag;
 
+use crate::atomic;
+use crate::decoder::{self, ReferenceImage, ReferenceSamples};
+use crate::icc;
+use crate::jp2box;
+use crate::metadata::{self, MetadataMode};
+use crate::profile;
+
 #[derive(Clone, Debug)]
 pub struct EncodeOptions {
     pub tile: String,
@@ -30,9 +37,40 @@ pub struct EncodeOptions {
     pub threads: usize,
     pub icc: Option<PathBuf>,
     pub order: String,
+    pub verify: bool,
+    pub quality: Quality,
+    /// Memory budget in bytes; images whose full decode would exceed it are streamed tile row by tile row.
+    pub max_memory: Option<u64>,
+    pub metadata: MetadataMode,
     pub toggles: Effective,
 }
 
+/// Rate control target for the codestream.
+#[derive(Clone, Debug)]
+pub enum Quality {
+    /// Reversible 5/3 wavelet, no truncation (default).
+    Lossless,
+    /// Irreversible 9/7 wavelet with a compression ratio (20 = 20:1).
+    Ratio(f32),
+    /// Irreversible 9/7 wavelet with a bits-per-pixel budget (all components together).
+    Bpp(f32),
+    /// Irreversible 9/7 wavelet with a PSNR target in dB (fixed quality allocation).
+    Psnr(f32),
+    /// One quality layer per ratio, decreasing; a trailing 0 makes the last layer
+    /// lossless (reversible 5/3), otherwise the whole stream is 9/7.
+    Layers(Vec<f32>),
+}
+
+impl Quality {
+    pub fn is_lossless(&self) -> bool {
+        match self {
+            Quality::Lossless => true,
+            Quality::Ratio(_) | Quality::Bpp(_) | Quality::Psnr(_) => false,
+            Quality::Layers(rates) => rates.last() == Some(&0.0),
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub struct Effective {
     pub avx2: bool,
@@ -93,51 +131,45 @@ struct TiffMeta {
     icc: Option<Vec<u8>>,
 }
 
-/// Reads basic metadata: X/Y resolution + unit, and best-effort ICC.
-/// Note: with tiff 0.9.x the ICC tag (34675) may come as a single Byte/Ascii,
-/// which is not a full profile. For archival use, prefer --icc <path.icc>.
-fn read_tiff_meta(p: &Path) -> Result<TiffMeta> {
-    let f = File::open(p)?;
-    let mut dec = Decoder::new(std::io::BufReader::new(f))?;
+/// XMP, IPTC and EXIF of page `ifd`, for `--metadata copy`.
+fn read_embedded_metadata(p: &Path, ifd: usize) -> Result<metadata::EmbeddedMetadata> {
+    let file = File::open(p).with_context(|| format!("Open {}", p.display()))?;
+    let file_len = file.metadata()?.len();
+    metadata::read_tiff_metadata(BufReader::new(file), file_len, ifd, p)
+}
+
+/// Reads basic metadata: X/Y resolution + unit, and the embedded ICC profile (tag 34675).
+fn read_tiff_meta(p: &Path, ifd: usize) -> Result<TiffMeta> {
+    let mut dec = open_tiff_page(p, ifd)?;
 
     // Resolution
     let mut xdpi = None;
     let mut ydpi = None;
     let mut unit = ResUnit::None;
 
-    if let Ok(v) = dec.get_tag(Tag::XResolution) {
-        if let Value::Rational(a, b) = v {
-            if b != 0 {
-                xdpi = Some(a as f64 / b as f64);
-            }
-        }
+    if let Ok(Value::Rational(a, b)) = dec.get_tag(Tag::XResolution)
+        && b != 0
+    {
+        xdpi = Some(a as f64 / b as f64);
     }
-    if let Ok(v) = dec.get_tag(Tag::YResolution) {
-        if let Value::Rational(a, b) = v {
-            if b != 0 {
-                ydpi = Some(a as f64 / b as f64);
-            }
-        }
+    if let Ok(Value::Rational(a, b)) = dec.get_tag(Tag::YResolution)
+        && b != 0
+    {
+        ydpi = Some(a as f64 / b as f64);
     }
-    if let Ok(v) = dec.get_tag(Tag::ResolutionUnit) {
-        if let Value::Short(u) = v {
-            unit = match u {
-                2 => ResUnit::Inch,
-                3 => ResUnit::Centimeter,
-                _ => ResUnit::None,
-            };
-        }
+    if let Ok(Value::Short(u)) = dec.get_tag(Tag::ResolutionUnit) {
+        unit = match u {
+            2 => ResUnit::Inch,
+            3 => ResUnit::Centimeter,
+            _ => ResUnit::None,
+        };
     }
 
-    // ICC (tag 34675) – often a single Byte/Ascii in tiff 0.9.x; not a full profile.
-    let mut icc: Option<Vec<u8>> = None;
-    if let Ok(v) = dec.get_tag(Tag::Unknown(34675)) {
-        match v {
-            Value::Byte(b) => icc = Some(vec![b]),
-            Value::Ascii(s) => icc = Some(s.into_bytes()),
-            _ => {}
-        }
-    }
+    // ICC (tag 34675, InterColorProfile): the whole UNDEFINED byte list
+    let icc = dec
+        .get_tag_u8_vec(Tag::IccProfile)
+        .ok()
+        .filter(|icc| !icc.is_empty());
 
     Ok(TiffMeta {
         xdpi,
@@ -147,22 +179,57 @@ fn read_tiff_meta(p: &Path) -> Result<TiffMeta> {
     })
 }
 
-// --- OpenJPEG helpers ----------------------------------------------------------
+// --- Multi-page TIFF ----------------------------------------------------------
 
-/// Attach ICC buffer to OpenJPEG image (takes ownership via raw pointer).
-unsafe fn attach_icc(img: *mut opj_image_t, icc: &[u8]) {
-    if img.is_null() || icc.is_empty() {
-        return;
-    }
-    let ptr = unsafe { malloc(icc.len()) };
-    if ptr.is_null() {
-        return;
+/// Full-resolution pages of a TIFF as IFD indices, in file order.
+pub struct TiffPages {
+    pub pages: Vec<usize>,
+    /// Reduced-resolution (thumbnail) and transparency-mask IFDs, never encoded.
+    pub skipped: usize,
+}
+
+/// Walks the IFD chain and classifies each IFD by NewSubfileType/SubfileType.
+pub fn tiff_pages(path: &Path) -> Result<TiffPages> {
+    let f = File::open(path).with_context(|| format!("Open {}", path.display()))?;
+    let mut dec = Decoder::new(BufReader::new(f))?;
+    let mut pages = Vec::new();
+    let mut skipped = 0;
+    for ifd in 0.. {
+        // NewSubfileType bit 0 = reduced resolution, bit 2 = transparency mask;
+        // the older SubfileType uses 2 for reduced resolution.
+        let new_type: u32 = dec
+            .find_tag_unsigned(Tag::NewSubfileType)
+            .ok()
+            .flatten()
+            .unwrap_or(0);
+        let old_type: u16 = dec
+            .find_tag_unsigned(Tag::SubfileType)
+            .ok()
+            .flatten()
+            .unwrap_or(1);
+        if new_type & 0b101 != 0 || old_type == 2 {
+            skipped += 1;
+        } else {
+            pages.push(ifd);
+        }
+        if !dec.more_images() {
+            break;
+        }
+        dec.next_image()
+            .with_context(|| format!("Read IFD {} of {}", ifd + 1, path.display()))?;
     }
-    unsafe {
-        std::ptr::copy_nonoverlapping(icc.as_ptr(), ptr as *mut u8, icc.len());
-        (*img).icc_profile_buf = ptr as *mut u8;
-        (*img).icc_profile_len = icc.len() as u32;
+    Ok(TiffPages { pages, skipped })
+}
+
+/// Opens a TIFF positioned at IFD `ifd`.
+fn open_tiff_page(path: &Path, ifd: usize) -> Result<Decoder<BufReader<File>>> {
+    let f = File::open(path).with_context(|| format!("Open {}", path.display()))?;
+    let mut dec = Decoder::new(BufReader::new(f))?;
+    if ifd > 0 {
+        dec.seek_to_image(ifd)
+            .with_context(|| format!("Seek to IFD {} of {}", ifd, path.display()))?;
     }
+    Ok(dec)
 }
 
 // --- JP2 Resolution box (embed DPI into jp2h/resc+resd) -----------------------
@@ -247,9 +314,9 @@ fn put_be_u32(x: u32, out: &mut Vec<u8>) {
     out.extend_from_slice(&x.to_be_bytes());
 }
 
-/// Inserts a 'res ' superbox with 'resc' + 'resd' into 'jp2h'.
+/// Rebuilds the 'jp2h' superbox with a new payload computed from the old one.
 /// If the structure is atypical (e.g., XLBox), it silently returns without changes.
-fn insert_resolution_box_jp2(path: &Path, xdpi: f64, ydpi: f64, unit: ResUnit) -> Result<()> {
+fn rewrite_jp2h(path: &Path, rebuild: impl FnOnce(&[u8]) -> Vec<u8>) -> Result<()> {
     let data = std::fs::read(path)?;
 
     // Locate 'jp2h' among top-level boxes.
@@ -267,6 +334,9 @@ fn insert_resolution_box_jp2(path: &Path, xdpi: f64, ydpi: f64, unit: ResUnit) -
             jp2h_pos = Some((off, blen as usize));
             break;
         }
+        if blen < 8 {
+            return Ok(());
+        }
         off += blen as usize;
     }
     let (jp2h_off, jp2h_len) = match jp2h_pos {
@@ -280,8 +350,29 @@ fn insert_resolution_box_jp2(path: &Path, xdpi: f64, ydpi: f64, unit: ResUnit) -
     if jp2h_payload_end > data.len() || jp2h_payload_start > jp2h_payload_end {
         return Ok(());
     }
-    let old_payload = &data[jp2h_payload_start..jp2h_payload_end];
+    let new_payload = rebuild(&data[jp2h_payload_start..jp2h_payload_end]);
+
+    // Rebuild file with the new 'jp2h'
+    let new_jp2h_len = 8u32.saturating_add(new_payload.len() as u32);
+
+    let mut new = Vec::with_capacity(data.len() + new_payload.len());
+    new.extend_from_slice(&data[..jp2h_off]);
+    put_be_u32(new_jp2h_len, &mut new);
+    new.extend_from_slice(b"jp2h");
+    new.extend_from_slice(&new_payload);
+    new.extend_from_slice(&data[jp2h_payload_end..]);
 
+    std::fs::write(path, &new)?;
+    Ok(())
+}
+
+/// Appends already serialized boxes at the end of the 'jp2h' superbox.
+fn append_to_jp2h(path: &Path, boxes: &[u8]) -> Result<()> {
+    rewrite_jp2h(path, |payload| [payload, boxes].concat())
+}
+
+/// Inserts a 'res ' superbox with 'resc' + 'resd' into 'jp2h'.
+fn insert_resolution_box_jp2(path: &Path, xdpi: f64, ydpi: f64, unit: ResUnit) -> Result<()> {
     // Convert DPI → PPM using TIFF unit
     let unit_ppm = match unit {
         ResUnit::Inch => PpmUnit::Inch,
@@ -314,21 +405,137 @@ fn insert_resolution_box_jp2(path: &Path, xdpi: f64, ydpi: f64, unit: ResUnit) -
     res_super.extend_from_slice(b"resd");
     res_super.extend_from_slice(&resd_p);
 
-    // Rebuild file with extended 'jp2h'
-    let new_jp2h_len = (jp2h_len as u32).saturating_add(res_super.len() as u32);
+    append_to_jp2h(path, &res_super)
+}
 
-    let mut new = Vec::with_capacity(data.len() + res_super.len());
-    new.extend_from_slice(&data[..jp2h_off]);
-    put_be_u32(new_jp2h_len, &mut new);
-    new.extend_from_slice(b"jp2h");
-    new.extend_from_slice(old_payload);
-    new.extend_from_slice(&res_super);
-    new.extend_from_slice(&data[jp2h_payload_end..]);
+// --- JP2 Colour specification box (ICC) ---------------------------------------
+
+/// Builds a 'colr' box carrying an ICC profile (METH 2 = restricted ICC, 3 = any ICC).
+fn build_icc_colr_box(icc: &[u8], meth: u8) -> Vec<u8> {
+    let mut v = Vec::with_capacity(8 + 3 + icc.len());
+    put_be_u32(8 + 3 + icc.len() as u32, &mut v);
+    v.extend_from_slice(b"colr");
+    v.push(meth); // METH
+    v.push(0); // PREC
+    v.push(0); // APPROX
+    v.extend_from_slice(icc);
+    v
+}
 
+/// Replaces the enumerated 'colr' box OpenJPEG wrote with one that embeds `icc`.
+/// (OpenJPEG does not copy `opj_image_t::icc_profile_buf` into the JP2 header on encode.)
+fn replace_colr_with_icc(path: &Path, icc: &[u8], meth: u8) -> Result<()> {
+    let colr = build_icc_colr_box(icc, meth);
+    rewrite_jp2h(path, |payload| {
+        let mut out = Vec::with_capacity(payload.len() + colr.len());
+        let mut off = 0usize;
+        let mut replaced = false;
+        while off + 8 <= payload.len() {
+            let len = be_u32(&payload[off..off + 4]) as usize;
+            if len < 8 || off + len > payload.len() {
+                break;
+            }
+            if &payload[off + 4..off + 8] == b"colr" && !replaced {
+                out.extend_from_slice(&colr);
+                replaced = true;
+            } else {
+                out.extend_from_slice(&payload[off..off + len]);
+            }
+            off += len;
+        }
+        out.extend_from_slice(&payload[off..]);
+        if !replaced {
+            out.extend_from_slice(&colr);
+        }
+        out
+    })
+}
+
+/// Adds `brand` to the compatibility list of the leading 'ftyp' box (no-op if present).
+fn add_ftyp_compatibility(path: &Path, brand: &[u8; 4]) -> Result<()> {
+    let data = std::fs::read(path)?;
+    // Signature box (12 bytes), then 'ftyp': LBox, TBox, BR, MinV, CL...
+    let ftyp = 12usize;
+    if data.len() < ftyp + 16 || &data[ftyp + 4..ftyp + 8] != b"ftyp" {
+        return Ok(());
+    }
+    let len = be_u32(&data[ftyp..ftyp + 4]) as usize;
+    if len < 16 || ftyp + len > data.len() {
+        return Ok(());
+    }
+    if data[ftyp + 16..ftyp + len]
+        .chunks_exact(4)
+        .any(|cl| cl == brand)
+    {
+        return Ok(());
+    }
+    let mut new = Vec::with_capacity(data.len() + 4);
+    new.extend_from_slice(&data[..ftyp]);
+    put_be_u32(len as u32 + 4, &mut new);
+    new.extend_from_slice(&data[ftyp + 4..ftyp + len]);
+    new.extend_from_slice(brand);
+    new.extend_from_slice(&data[ftyp + len..]);
     std::fs::write(path, &new)?;
     Ok(())
 }
 
+/// ICC data colour space signature matching an OpenJPEG colour space.
+fn icc_color_space(clrspc: COLOR_SPACE) -> &'static [u8; 4] {
+    match clrspc {
+        COLOR_SPACE::OPJ_CLRSPC_GRAY => b"GRAY",
+        COLOR_SPACE::OPJ_CLRSPC_CMYK => b"CMYK",
+        _ => b"RGB ",
+    }
+}
+
+// --- JP2 Channel definition box (alpha) ----------------------------------------
+
+/// Kind of the TIFF extra sample, mapped onto the JP2 'cdef' channel type.
+#[derive(Clone, Copy, Debug)]
+enum AlphaKind {
+    /// ExtraSamples = 2 → cdef Typ 1 (opacity)
+    Unassociated,
+    /// ExtraSamples = 1 → cdef Typ 2 (premultiplied opacity)
+    Associated,
+    /// ExtraSamples = 0 or missing → cdef Typ 65535 (unspecified)
+    Unspecified,
+}
+
+impl AlphaKind {
+    fn from_extra_samples(extra: Option<u16>) -> Self {
+        match extra {
+            Some(1) => AlphaKind::Associated,
+            Some(2) => AlphaKind::Unassociated,
+            _ => AlphaKind::Unspecified,
+        }
+    }
+}
+
+/// Builds a 'cdef' box: colour channels map 1:1 onto colours, the last one is alpha.
+fn build_cdef_box(channels: u32, alpha: AlphaKind) -> Vec<u8> {
+    let (alpha_typ, alpha_asoc) = match alpha {
+        AlphaKind::Unassociated => (1u16, 0u16),
+        AlphaKind::Associated => (2, 0),
+        AlphaKind::Unspecified => (65535, 65535),
+    };
+    let n = channels as u16;
+    let mut v = Vec::with_capacity(8 + 2 + 6 * n as usize);
+    put_be_u32(8 + 2 + 6 * n as u32, &mut v);
+    v.extend_from_slice(b"cdef");
+    v.extend_from_slice(&n.to_be_bytes());
+    for cn in 0..n {
+        let (typ, asoc) = if cn + 1 == n {
+            (alpha_typ, alpha_asoc)
+        } else {
+            (0, cn + 1)
+        };
+        v.extend_from_slice(&cn.to_be_bytes());
+        v.extend_from_slice(&typ.to_be_bytes());
+        v.extend_from_slice(&asoc.to_be_bytes());
+    }
+    v
+}
+
 // --- XMP DPI fallback ----------------------------------------------------------
 
 /// Builds a minimal XMP packet carrying TIFF-style resolution metadata.
@@ -352,18 +559,15 @@ fn build_xmp_with_dpi(xdpi: f64, ydpi: f64, unit: ResUnit) -> String {
     )
 }
 
-/// Appends an XMP UUID box at the end of the JP2 file.
-fn append_jp2_xmp_box(path: &Path, xmp_xml: &[u8]) -> Result<()> {
-    const XMP_UUID: [u8; 16] = [
-        0xBE, 0x7A, 0xCF, 0xCB, 0x97, 0xA9, 0x42, 0xE8, 0x9C, 0x71, 0x99, 0x94, 0x91, 0xE3, 0xAF,
-        0xAC,
-    ];
+/// Appends a 'uuid' box at the end of the JP2 file.
+fn append_jp2_uuid_box(path: &Path, uuid: &[u8; 16], payload: &[u8]) -> Result<()> {
+    let total_len = u32::try_from(8 + 16 + payload.len())
+        .map_err(|_| anyhow!("Metadata block of {} bytes is too large", payload.len()))?;
     let mut f = fs::OpenOptions::new().append(true).open(path)?;
-    let total_len = 8u32 + 16u32 + xmp_xml.len() as u32;
     f.write_all(&total_len.to_be_bytes())?;
     f.write_all(b"uuid")?;
-    f.write_all(&XMP_UUID)?;
-    f.write_all(xmp_xml)?;
+    f.write_all(uuid)?;
+    f.write_all(payload)?;
     Ok(())
 }
 
@@ -428,14 +632,14 @@ unsafe fn deinterleave_rgb8_row_avx2(
     while x + 8 <= n {
         // Build byte offsets for 8 consecutive pixels
         let off_r = [
-            (3 * (x + 0) + 0) as i32,
-            (3 * (x + 1) + 0) as i32,
-            (3 * (x + 2) + 0) as i32,
-            (3 * (x + 3) + 0) as i32,
-            (3 * (x + 4) + 0) as i32,
-            (3 * (x + 5) + 0) as i32,
-            (3 * (x + 6) + 0) as i32,
-            (3 * (x + 7) + 0) as i32,
+            (3 * x) as i32,
+            (3 * (x + 1)) as i32,
+            (3 * (x + 2)) as i32,
+            (3 * (x + 3)) as i32,
+            (3 * (x + 4)) as i32,
+            (3 * (x + 5)) as i32,
+            (3 * (x + 6)) as i32,
+            (3 * (x + 7)) as i32,
         ];
         let off_g = [
             off_r[0] + 1,
@@ -514,8 +718,8 @@ fn next_pow2_at_least(x: i32, min: i32) -> i32 {
     p
 }
 
-/// Enable precincts and fill per-resolution sizes with the NDK pattern:
-/// for R levels: all but the last 2 resolutions use 128x128, the last 2 use 256x256.
+/// Enable precincts and fill per-resolution sizes with the NDK pattern
+/// (`profile::NDK`): the 2 lowest resolutions use 256x256, the rest 128x128.
 /// (E.g., for 6 levels → 4×128 + 2×256).
 fn fill_precincts(
     enc: &mut openjpeg_sys::opj_cparameters_t,
@@ -527,10 +731,10 @@ fn fill_precincts(
     enc.res_spec = levels.min(32) as i32;
 
     let lvls = levels.min(32);
+    let sizes = profile::NDK.precincts.sizes(lvls);
     for r in 0..lvls {
-        // r==0 je nejjemnější (full-res). Poslední 2 (r = lvls-2, lvls-1) dáme 256, jinak 128.
-        let use_256 = r >= lvls.saturating_sub(2);
-        let mut pw = if use_256 { 256 } else { 128 };
+        // r==0 je nejjemnější (full-res); `sizes` začíná nejnižším rozlišením.
+        let mut pw = sizes[(lvls - 1 - r) as usize] as i32;
         let mut ph = pw;
 
         // Must be >= code-block and power of two
@@ -573,29 +777,340 @@ fn set_openjpeg_extra_options(codec: *mut opj_codec_t, eff: &Effective) -> Resul
     Ok(())
 }
 
+// --- Streaming (tile-by-tile) encode -------------------------------------------
+
+/// Rough peak memory of the in-memory path: interleaved TIFF buffer + i32 component planes.
+fn full_encode_memory(w: u32, h: u32, channels: u32, sample_bytes: u64) -> u64 {
+    w as u64 * h as u64 * channels as u64 * (sample_bytes + 4)
+}
+
+/// Rough peak memory of the streaming path: the cached TIFF strip/tile row, the
+/// band of full-width rows for one JP2 tile row and OpenJPEG's per-tile buffers.
+fn streaming_encode_memory(grid: &TileGrid, sample_bytes: u64, chunk_h: u32) -> u64 {
+    let row = grid.width as u64 * grid.channels as u64 * sample_bytes;
+    let tile_w = grid.tile_w.min(grid.width) as u64;
+    let tile_h = grid.tile_h.min(grid.height) as u64;
+    let tile = tile_w * tile_h * grid.channels as u64;
+    row * chunk_h.min(grid.height) as u64 + row * tile_h + tile * (sample_bytes + 8)
+}
+
+/// Expected peak memory for encoding page `ifd` of `input` (used by `--jobs`
+/// to decide how many files fit in memory at once).
+pub fn estimate_encode_memory(input: &Path, ifd: usize, options: &EncodeOptions) -> Result<u64> {
+    let mut dec = open_tiff_page(input, ifd)?;
+    let (w, h) = dec.dimensions()?;
+    let color = dec.colortype()?;
+    let sample_bytes: u64 = if color.bit_depth() <= 8 { 1 } else { 2 };
+    let full = full_encode_memory(w, h, color.num_samples() as u32, sample_bytes);
+    // Larger images are streamed within the --max-memory budget
+    Ok(options.max_memory.map_or(full, |budget| full.min(budget)))
+}
+
+fn fmt_mib(bytes: u64) -> String {
+    format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0))
+}
+
+/// Image and JP2 tile geometry shared by the streaming writer and verifier.
+struct TileGrid {
+    width: u32,
+    height: u32,
+    channels: u32,
+    tile_w: u32,
+    tile_h: u32,
+}
+
+impl TileGrid {
+    fn tiles_x(&self) -> u32 {
+        self.width.div_ceil(self.tile_w)
+    }
+
+    /// (y0, y1) of every tile row, top to bottom.
+    fn tile_rows(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        (0..self.height)
+            .step_by(self.tile_h as usize)
+            .map(|y0| (y0, (y0 + self.tile_h).min(self.height)))
+    }
+}
+
+impl PixelBuf {
+    fn empty_like(&self, capacity: usize) -> PixelBuf {
+        match self {
+            PixelBuf::U8(_) => PixelBuf::U8(Vec::with_capacity(capacity)),
+            PixelBuf::U16(_) => PixelBuf::U16(Vec::with_capacity(capacity)),
+        }
+    }
+
+    fn as_reference(&self) -> ReferenceSamples<'_> {
+        match self {
+            PixelBuf::U8(buf) => ReferenceSamples::U8(buf),
+            PixelBuf::U16(buf) => ReferenceSamples::U16(buf),
+        }
+    }
+}
+
+/// Reads full-width rows from an interleaved TIFF while keeping only the
+/// current strip (or row of TIFF tiles) in memory.
+struct TiffBandReader<R: Read + Seek> {
+    dec: Decoder<R>,
+    width: u32,
+    height: u32,
+    channels: usize,
+    chunk_w: u32,
+    chunk_h: u32,
+    chunks_across: u32,
+    cached: Option<(u32, PixelBuf)>,
+}
+
+impl<R: Read + Seek> TiffBandReader<R> {
+    fn new(mut dec: Decoder<R>, channels: u32) -> Result<Self> {
+        let (width, height) = dec.dimensions()?;
+        let planar = dec
+            .find_tag_unsigned::<u16>(Tag::PlanarConfiguration)
+            .ok()
+            .flatten()
+            .unwrap_or(1);
+        if planar != 1 {
+            return Err(anyhow!(
+                "Streaming encode needs interleaved samples (PlanarConfiguration=1, got {})",
+                planar
+            ));
+        }
+        let (chunk_w, chunk_h) = dec.chunk_dimensions();
+        if chunk_w == 0 || chunk_h == 0 {
+            return Err(anyhow!(
+                "TIFF has empty strips/tiles ({}x{})",
+                chunk_w,
+                chunk_h
+            ));
+        }
+        Ok(Self {
+            dec,
+            width,
+            height,
+            channels: channels as usize,
+            chunk_w,
+            chunk_h,
+            chunks_across: width.div_ceil(chunk_w),
+            cached: None,
+        })
+    }
+
+    /// Returns rows `y0..y1` as one interleaved buffer.
+    fn read_rows(&mut self, y0: u32, y1: u32) -> Result<PixelBuf> {
+        let row_len = self.width as usize * self.channels;
+        let mut band: Option<PixelBuf> = None;
+        for y in y0..y1 {
+            let chunk_row = y / self.chunk_h;
+            if self.cached.as_ref().map(|(r, _)| *r) != Some(chunk_row) {
+                // Drop the previous chunk row before decoding the next one
+                self.cached = None;
+                self.cached = Some((chunk_row, self.load_chunk_row(chunk_row)?));
+            }
+            let Some((_, rows)) = &self.cached else {
+                unreachable!("chunk row loaded above")
+            };
+            let start = (y - chunk_row * self.chunk_h) as usize * row_len;
+            let range = start..start + row_len;
+            let dst = band.get_or_insert_with(|| rows.empty_like((y1 - y0) as usize * row_len));
+            match (dst, rows) {
+                (PixelBuf::U8(dst), PixelBuf::U8(src)) => dst.extend_from_slice(&src[range]),
+                (PixelBuf::U16(dst), PixelBuf::U16(src)) => dst.extend_from_slice(&src[range]),
+                _ => return Err(anyhow!("TIFF strips/tiles mix sample sizes")),
+            }
+        }
+        band.ok_or_else(|| anyhow!("Empty row range {}..{}", y0, y1))
+    }
+
+    /// Decodes every strip/tile of one chunk row into full-width interleaved rows.
+    fn load_chunk_row(&mut self, chunk_row: u32) -> Result<PixelBuf> {
+        let rows = self.chunk_h.min(self.height - chunk_row * self.chunk_h) as usize;
+        let row_len = self.width as usize * self.channels;
+        let mut out: Option<PixelBuf> = None;
+        for col in 0..self.chunks_across {
+            let idx = chunk_row * self.chunks_across + col;
+            let (data_w, data_h) = self.dec.chunk_data_dimensions(idx);
+            let chunk = self
+                .dec
+                .read_chunk(idx)
+                .with_context(|| format!("Read TIFF strip/tile {}", idx))?;
+            if out.is_none() {
+                out = Some(match &chunk {
+                    DecodingResult::U8(_) => PixelBuf::U8(vec![0; rows * row_len]),
+                    DecodingResult::U16(_) => PixelBuf::U16(vec![0; rows * row_len]),
+                    _ => return Err(anyhow!("Unsupported TIFF buffer")),
+                });
+            }
+            let x0 = (col * self.chunk_w) as usize * self.channels;
+            let chunk_len = data_w as usize * self.channels;
+            let rows = (data_h as usize).min(rows);
+            match (out.as_mut(), &chunk) {
+                (Some(PixelBuf::U8(dst)), DecodingResult::U8(src)) => {
+                    copy_chunk(dst, src, row_len, x0, chunk_len, rows)
+                }
+                (Some(PixelBuf::U16(dst)), DecodingResult::U16(src)) => {
+                    copy_chunk(dst, src, row_len, x0, chunk_len, rows)
+                }
+                _ => return Err(anyhow!("TIFF strips/tiles mix sample sizes")),
+            }
+        }
+        out.ok_or_else(|| anyhow!("TIFF has no strips/tiles"))
+    }
+}
+
+/// Places a decoded TIFF chunk (`rows` rows of `chunk_len` samples) at sample offset `x0`.
+fn copy_chunk<T: Copy>(
+    dst: &mut [T],
+    src: &[T],
+    row_len: usize,
+    x0: usize,
+    chunk_len: usize,
+    rows: usize,
+) {
+    for (y, src_row) in src.chunks_exact(chunk_len).take(rows).enumerate() {
+        let start = y * row_len + x0;
+        dst[start..start + chunk_len].copy_from_slice(src_row);
+    }
+}
+
+/// Repacks one tile from interleaved band rows into the planar layout
+/// `opj_write_tile` expects (component after component, native-endian samples).
+fn pack_tile<T: Copy, const N: usize>(
+    band: &[T],
+    grid: &TileGrid,
+    x0: u32,
+    rows: u32,
+    out: &mut Vec<u8>,
+    to_bytes: fn(T) -> [u8; N],
+) {
+    let ch = grid.channels as usize;
+    let row_len = grid.width as usize * ch;
+    let x1 = (x0 + grid.tile_w).min(grid.width) as usize;
+    out.clear();
+    for c in 0..ch {
+        for y in 0..rows as usize {
+            let row = &band[y * row_len..(y + 1) * row_len];
+            for x in x0 as usize..x1 {
+                out.extend_from_slice(&to_bytes(row[x * ch + c]));
+            }
+        }
+    }
+}
+
+/// Encodes the image tile row by tile row; only one band of rows is decoded at a time.
+fn write_tiles_streaming<R: Read + Seek>(
+    codec: *mut opj_codec_t,
+    stream: *mut opj_stream_t,
+    reader: &mut TiffBandReader<R>,
+    grid: &TileGrid,
+) -> Result<()> {
+    let tiles_x = grid.tiles_x();
+    let mut tile = Vec::new();
+    for (ty, (y0, y1)) in grid.tile_rows().enumerate() {
+        let band = reader.read_rows(y0, y1)?;
+        for tx in 0..tiles_x {
+            let x0 = tx * grid.tile_w;
+            match &band {
+                PixelBuf::U8(buf) => pack_tile(buf, grid, x0, y1 - y0, &mut tile, |v| [v]),
+                PixelBuf::U16(buf) => {
+                    pack_tile(buf, grid, x0, y1 - y0, &mut tile, u16::to_ne_bytes)
+                }
+            }
+            let tile_index = ty as u32 * tiles_x + tx;
+            let ok = unsafe {
+                opj_write_tile(
+                    codec,
+                    tile_index,
+                    tile.as_mut_ptr(),
+                    tile.len() as u32,
+                    stream,
+                )
+            } != 0;
+            if !ok {
+                return Err(anyhow!("opj_write_tile failed (tile {})", tile_index));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Streaming counterpart of the in-memory `--verify`: re-reads the TIFF band by
+/// band and compares each decoded JP2 tile with it.
+fn verify_streaming(
+    input: &Path,
+    ifd: usize,
+    output: &Path,
+    threads: i32,
+    grid: &TileGrid,
+) -> Result<()> {
+    let dec = open_tiff_page(input, ifd)?.with_limits(Limits::unlimited());
+    let mut reader = TiffBandReader::new(dec, grid.channels)?;
+    let mut tiles = decoder::TileVerifier::open(output, threads)?;
+    for (y0, y1) in grid.tile_rows() {
+        let band = reader.read_rows(y0, y1)?;
+        let reference = ReferenceImage {
+            width: grid.width,
+            height: y1 - y0,
+            channels: grid.channels,
+            samples: band.as_reference(),
+        };
+        for _ in 0..grid.tiles_x() {
+            tiles.verify_next(&reference, y0)?;
+        }
+    }
+    tiles.finish()
+}
+
 // --- Main conversion -----------------------------------------------------------
 
-pub fn encode_tiff_to_jp2(input: &Path, output: &Path, options: &EncodeOptions) -> Result<()> {
-    eprintln!("  [DEBUG] Starting conversion for: {}", input.display());
+/// Encodes the TIFF page stored in IFD `ifd` (see [`tiff_pages`]) to a JP2 file.
+/// `output` appears only once the JP2 is complete, boxes and `--verify` included.
+pub fn encode_tiff_to_jp2(
+    input: &Path,
+    ifd: usize,
+    output: &Path,
+    options: &EncodeOptions,
+) -> Result<()> {
+    atomic::write_atomically(output, |temp| encode_page(input, ifd, temp, options))
+}
+
+fn encode_page(input: &Path, ifd: usize, output: &Path, options: &EncodeOptions) -> Result<()> {
+    eprintln!(
+        "  [DEBUG] Starting conversion for: {} (IFD {})",
+        input.display(),
+        ifd
+    );
 
     // Normalize flags once for this conversion
     let eff = options.toggles;
 
     // Metadata (DPI/ICC)
     eprintln!("  [DEBUG] Reading TIFF metadata");
-    let meta = read_tiff_meta(input).unwrap_or(TiffMeta {
+    let meta = read_tiff_meta(input, ifd).unwrap_or(TiffMeta {
         xdpi: None,
         ydpi: None,
         unit: ResUnit::None,
         icc: None,
     });
+    let embedded = match options.metadata {
+        MetadataMode::None => metadata::EmbeddedMetadata::default(),
+        // Metadata is secondary: a broken block must not cost the image
+        MetadataMode::Copy => read_embedded_metadata(input, ifd).unwrap_or_else(|e| {
+            eprintln!(
+                "WARN {}: XMP/IPTC/EXIF not copied: {:#}",
+                input.display(),
+                e
+            );
+            metadata::EmbeddedMetadata::default()
+        }),
+    };
 
     // Decode TIFF
     eprintln!("  [DEBUG] Opening TIFF file");
-    let f = File::open(input).with_context(|| format!("Open {}", input.display()))?;
-
-    eprintln!("  [DEBUG] Creating decoder");
-    let mut dec = Decoder::new(f)?;
+    let mut dec = open_tiff_page(input, ifd)?;
+    if options.max_memory.is_some() {
+        // The --max-memory budget replaces tiff's fixed 256 MiB buffer cap
+        dec = dec.with_limits(Limits::unlimited());
+    }
 
     eprintln!("  [DEBUG] Reading dimensions");
     let (w, h) = dec.dimensions()?;
@@ -605,39 +1120,125 @@ pub fn encode_tiff_to_jp2(input: &Path, output: &Path, options: &EncodeOptions)
     let ct = dec.colortype()?;
     eprintln!("  [DEBUG] Color type: {:?}", ct);
 
-    let (bit_depth, channels, rgb) = match ct {
-        ColorType::Gray(n) => (n as u32, 1u32, false),
-        ColorType::RGB(n) => (n as u32, 3u32, true),
-        ColorType::RGBA(n) => {
-            return Err(anyhow!(
-                "TIFF has alpha channel ({}-bit). Please flatten/remove alpha.",
-                n
-            ));
-        }
-        ColorType::CMYK(n) => {
-            return Err(anyhow!(
-                "CMYK {}-bit is not supported (convert to RGB/Gray).",
-                n
-            ));
-        }
+    // ExtraSamples (338) tells whether the extra sample is (premultiplied) alpha.
+    let extra_samples: Option<u16> = dec
+        .find_tag_unsigned_vec::<u16>(Tag::ExtraSamples)
+        .ok()
+        .flatten()
+        .and_then(|v| v.first().copied());
+
+    let (bit_depth, channels, clrspc, alpha) = match ct {
+        ColorType::Gray(n) => (n as u32, 1u32, COLOR_SPACE::OPJ_CLRSPC_GRAY, None),
+        ColorType::RGB(n) => (n as u32, 3u32, COLOR_SPACE::OPJ_CLRSPC_SRGB, None),
+        ColorType::RGBA(n) => (
+            n as u32,
+            4u32,
+            COLOR_SPACE::OPJ_CLRSPC_SRGB,
+            Some(AlphaKind::from_extra_samples(extra_samples)),
+        ),
+        // tiff reports gray + alpha as two-sample Multiband
+        ColorType::Multiband {
+            bit_depth,
+            num_samples: 2,
+        } if extra_samples.is_some() => (
+            bit_depth as u32,
+            2u32,
+            COLOR_SPACE::OPJ_CLRSPC_GRAY,
+            Some(AlphaKind::from_extra_samples(extra_samples)),
+        ),
+        // Four plain components; the colour space travels in the ICC (or enumcs 12 without one)
+        ColorType::CMYK(n) => (n as u32, 4u32, COLOR_SPACE::OPJ_CLRSPC_CMYK, None),
         other => return Err(anyhow!("Unsupported TIFF: {:?}", other)),
     };
+    let rgb = matches!(clrspc, COLOR_SPACE::OPJ_CLRSPC_SRGB);
     eprintln!(
-        "  [DEBUG] Bit depth: {}, Channels: {}, RGB: {}",
-        bit_depth, channels, rgb
+        "  [DEBUG] Bit depth: {}, Channels: {}, Color space: {:?}, Alpha: {:?}",
+        bit_depth, channels, clrspc, alpha
     );
 
-    eprintln!("  [DEBUG] Reading image data");
-    let pixels = match dec.read_image()? {
-        DecodingResult::U8(buf) => {
-            eprintln!("  [DEBUG] Buffer type: U8, size: {}", buf.len());
-            PixelBuf::U8(buf)
+    // Parse tile size
+    let (tile_w, tile_h) = parse_wh(&options.tile)?;
+    if tile_w == 0 || tile_h == 0 {
+        return Err(anyhow!("Invalid tile size {}x{}", tile_w, tile_h));
+    }
+    let grid = TileGrid {
+        width: w,
+        height: h,
+        channels,
+        tile_w,
+        tile_h,
+    };
+
+    // Whole image in memory unless that would exceed --max-memory
+    let sample_bytes: u64 = if bit_depth <= 8 { 1 } else { 2 };
+    let streaming = match options.max_memory {
+        Some(budget) if full_encode_memory(w, h, channels, sample_bytes) > budget => {
+            let (_, chunk_h) = dec.chunk_dimensions();
+            let needed = streaming_encode_memory(&grid, sample_bytes, chunk_h);
+            if needed > budget {
+                return Err(anyhow!(
+                    "--max-memory {} is too small: streaming {}x{} needs ~{} \
+                     (TIFF strips/tiles of {} rows, --tile {}x{}); use a smaller --tile",
+                    fmt_mib(budget),
+                    w,
+                    h,
+                    fmt_mib(needed),
+                    chunk_h,
+                    tile_w,
+                    tile_h
+                ));
+            }
+            true
         }
-        DecodingResult::U16(buf) => {
-            eprintln!("  [DEBUG] Buffer type: U16, size: {}", buf.len());
-            PixelBuf::U16(buf)
+        _ => false,
+    };
+
+    let (pixels, mut bands) = if streaming {
+        (None, Some(TiffBandReader::new(dec, channels)?))
+    } else {
+        eprintln!("  [DEBUG] Reading image data");
+        let pixels = match dec.read_image()? {
+            DecodingResult::U8(buf) => {
+                eprintln!("  [DEBUG] Buffer type: U8, size: {}", buf.len());
+                PixelBuf::U8(buf)
+            }
+            DecodingResult::U16(buf) => {
+                eprintln!("  [DEBUG] Buffer type: U16, size: {}", buf.len());
+                PixelBuf::U16(buf)
+            }
+            _ => return Err(anyhow!("Unsupported TIFF buffer")),
+        };
+        (Some(pixels), None)
+    };
+
+    // ICC: override from --icc or the TIFF profile, checked against the image colour space
+    // (written into 'colr' after encoding, with the method the profile qualifies for)
+    let expected_space = icc_color_space(clrspc);
+    let icc: Option<(Vec<u8>, u8)> = if let Some(icc_path) = &options.icc {
+        eprintln!("  [DEBUG] Loading ICC profile from: {}", icc_path.display());
+        let mut buf = Vec::new();
+        File::open(icc_path)
+            .with_context(|| format!("Read ICC {}", icc_path.display()))?
+            .read_to_end(&mut buf)?;
+        let (profile, header) = icc::validate_for_image(&buf, expected_space)
+            .map_err(|e| anyhow!("--icc {}: {}", icc_path.display(), e))?;
+        Some((profile.to_vec(), icc::colr_method(profile, &header)))
+    } else if let Some(icc) = &meta.icc {
+        eprintln!("  [DEBUG] Using TIFF ICC profile (size: {})", icc.len());
+        // A broken embedded profile is dropped rather than copied into the archive
+        match icc::validate_for_image(icc, expected_space) {
+            Ok((profile, header)) => Some((profile.to_vec(), icc::colr_method(profile, &header))),
+            Err(e) => {
+                eprintln!(
+                    "WARN {}: ignoring embedded ICC profile: {}",
+                    input.display(),
+                    e
+                );
+                None
+            }
         }
-        _ => return Err(anyhow!("Unsupported TIFF buffer")),
+    } else {
+        None
     };
 
     eprintln!("  [DEBUG] Creating OpenJPEG image components");
@@ -655,15 +1256,14 @@ pub fn encode_tiff_to_jp2(input: &Path, output: &Path, options: &EncodeOptions)
         })
         .collect();
 
-    let clrspc = if rgb {
-        COLOR_SPACE::OPJ_CLRSPC_SRGB
-    } else {
-        COLOR_SPACE::OPJ_CLRSPC_GRAY
-    };
-
     eprintln!("  [DEBUG] Creating OpenJPEG image");
     let img: *mut opj_image_t = unsafe {
-        let p = opj_image_create(channels, cmpts.as_mut_ptr(), clrspc);
+        // Streaming: header only, the samples arrive through opj_write_tile
+        let p = if streaming {
+            opj_image_tile_create(channels, cmpts.as_mut_ptr(), clrspc)
+        } else {
+            opj_image_create(channels, cmpts.as_mut_ptr(), clrspc)
+        };
         if p.is_null() {
             return Err(anyhow!("opj_image_create failed"));
         }
@@ -674,39 +1274,24 @@ pub fn encode_tiff_to_jp2(input: &Path, output: &Path, options: &EncodeOptions)
         p
     };
 
-    // ICC: override from --icc or use best-effort TIFF ICC
-    if let Some(icc_path) = &options.icc {
-        eprintln!("  [DEBUG] Loading ICC profile from: {}", icc_path.display());
-        let mut buf = Vec::new();
-        File::open(icc_path)
-            .with_context(|| format!("Read ICC {}", icc_path.display()))?
-            .read_to_end(&mut buf)?;
-        unsafe { attach_icc(img, &buf) };
-    } else if let Some(icc) = &meta.icc {
-        eprintln!("  [DEBUG] Using TIFF ICC profile (size: {})", icc.len());
-        unsafe { attach_icc(img, icc) };
-    }
-
     eprintln!("  [DEBUG] Filling component planes");
-    match pixels {
-        PixelBuf::U8(buf) => {
+    match &pixels {
+        Some(PixelBuf::U8(buf)) => {
             eprintln!("  [DEBUG] Filling U8 components");
             // <<< CHANGED: pass eff.avx2 >>>
-            fill_components_u8(img, &buf, w, h, channels, eff.avx2)?
+            fill_components_u8(img, buf, w, h, channels, eff.avx2)?
         }
-        PixelBuf::U16(buf) => {
+        Some(PixelBuf::U16(buf)) => {
             eprintln!("  [DEBUG] Filling U16 components");
             // <<< CHANGED: pass eff.avx2 >>>
-            fill_components_u16(img, &buf, w, h, channels, eff.avx2)?
+            fill_components_u16(img, buf, w, h, channels, eff.avx2)?
         }
+        None => {}
     }
 
     // Encoder parameters (lossless 5/3, tiles, code-blocks, levels)
     eprintln!("  [DEBUG] Setting encoder parameters");
 
-    // Parse tile size
-    let (tile_w, tile_h) = parse_wh(&options.tile)?;
-
     // Parse code-block size + validate (must be power of two in range 4..=1024)
     let (blk_w, blk_h) = parse_wh(&options.block)?;
     let is_pow2 = |v: u32| v != 0 && (v & (v - 1)) == 0;
@@ -733,8 +1318,8 @@ pub fn encode_tiff_to_jp2(input: &Path, output: &Path, options: &EncodeOptions)
         p.assume_init()
     };
 
-    // Reversible 5/3 transform (lossless)
-    enc_params.irreversible = 0;
+    // Reversible 5/3 transform (lossless) unless a lossy target was requested
+    enc_params.irreversible = if options.quality.is_lossless() { 0 } else { 1 };
 
     // Selective arithmetic coding bypass (code-block style 'LAZY')
     if eff.bypass {
@@ -755,9 +1340,6 @@ pub fn encode_tiff_to_jp2(input: &Path, output: &Path, options: &EncodeOptions)
     // Resolution levels
     enc_params.numresolution = levels as i32;
 
-    // Single quality layer (lossless)
-    enc_params.tcp_numlayers = 1;
-
     // Progression order
     enc_params.prog_order = parse_order(&options.order);
 
@@ -769,7 +1351,7 @@ pub fn encode_tiff_to_jp2(input: &Path, output: &Path, options: &EncodeOptions)
         enc_params.csty |= J2K_CCP_CSTY_EPH;
     }
 
-    // Enable reversible MCT for RGB if allowed
+    // Enable MCT for RGB if allowed: RCT when lossless, ICT with 9/7 (never for CMYK: MCT would mix C, M and Y)
     if rgb && eff.mct {
         enc_params.tcp_mct = 1;
     }
@@ -787,11 +1369,45 @@ pub fn encode_tiff_to_jp2(input: &Path, output: &Path, options: &EncodeOptions)
         enc_params.tp_flag = b'R' as _;
     }
 
-    // Single quality layer, explicitly lossless
-    enc_params.tcp_numlayers = 1; // already set, keep it
-    enc_params.tcp_rates[0] = 0.0; // 0.0 = lossless in OpenJPEG
-    enc_params.cp_disto_alloc = 1; // use rate/distortion allocation (required when using rates)
-    enc_params.cp_fixed_quality = 0; // make sure we're not using fixed PSNR mode
+    // Quality layers: one lossless, rate-limited or PSNR-limited layer, or an explicit rate ladder
+    enc_params.tcp_numlayers = 1;
+    match &options.quality {
+        Quality::Lossless => {
+            enc_params.tcp_rates[0] = 0.0; // 0.0 = lossless in OpenJPEG
+            enc_params.cp_disto_alloc = 1; // use rate/distortion allocation (required when using rates)
+            enc_params.cp_fixed_quality = 0; // make sure we're not using fixed PSNR mode
+        }
+        Quality::Ratio(ratio) => {
+            enc_params.tcp_rates[0] = *ratio;
+            enc_params.cp_disto_alloc = 1;
+            enc_params.cp_fixed_quality = 0;
+        }
+        Quality::Bpp(bpp) => {
+            // OpenJPEG only knows ratios: uncompressed bits per pixel / target bits per pixel
+            enc_params.tcp_rates[0] = (channels * bit_depth) as f32 / bpp;
+            enc_params.cp_disto_alloc = 1;
+            enc_params.cp_fixed_quality = 0;
+        }
+        Quality::Psnr(psnr) => {
+            enc_params.tcp_distoratio[0] = *psnr;
+            enc_params.cp_disto_alloc = 0;
+            enc_params.cp_fixed_quality = 1;
+        }
+        Quality::Layers(rates) => {
+            if rates.is_empty() || rates.len() > enc_params.tcp_rates.len() {
+                unsafe { opj_image_destroy(img) };
+                return Err(anyhow!(
+                    "--layers needs 1..={} rates (got {})",
+                    enc_params.tcp_rates.len(),
+                    rates.len()
+                ));
+            }
+            enc_params.tcp_numlayers = rates.len() as i32;
+            enc_params.tcp_rates[..rates.len()].copy_from_slice(rates);
+            enc_params.cp_disto_alloc = 1;
+            enc_params.cp_fixed_quality = 0;
+        }
+    }
 
     eprintln!("  [DEBUG] Creating OpenJPEG codec");
     let codec: *mut opj_codec_t = unsafe { opj_create_compress(CODEC_FORMAT::OPJ_CODEC_JP2) };
@@ -859,40 +1475,93 @@ pub fn encode_tiff_to_jp2(input: &Path, output: &Path, options: &EncodeOptions)
     }
 
     eprintln!("  [DEBUG] Encoding");
-    let encoded = unsafe { opj_encode(codec, stream) } != 0;
+    let encoded = match bands.as_mut() {
+        Some(reader) => write_tiles_streaming(codec, stream, reader, &grid),
+        None if unsafe { opj_encode(codec, stream) } != 0 => Ok(()),
+        None => Err(anyhow!("Compression failed (opj_encode)")),
+    };
 
     eprintln!("  [DEBUG] Ending compression");
-    let ended = unsafe { opj_end_compress(codec, stream) } != 0;
+    let ended = encoded.is_ok() && unsafe { opj_end_compress(codec, stream) } != 0;
 
-    if !encoded || !ended {
-        return Err(anyhow!("Compression failed (opj_encode/opj_end_compress)"));
+    // Release the stream (closes the file) before the JP2 is re-read below.
+    unsafe {
+        opj_stream_destroy(stream);
+        opj_destroy_codec(codec);
+        opj_image_destroy(img);
+    }
+
+    encoded?;
+    if !ended {
+        return Err(anyhow!("Compression failed (opj_end_compress)"));
     }
 
     eprintln!("  [DEBUG] Compression completed successfully");
 
-    // JP2 Resolution box (visible DPI for most viewers)
-    // <<< CHANGED: use eff.dpi_box >>>
-    if eff.dpi_box {
-        if let (Some(xdpi), Some(ydpi)) = (meta.xdpi, meta.ydpi) {
-            eprintln!("  [DEBUG] Adding resolution box");
-            let _ = insert_resolution_box_jp2(output, xdpi, ydpi, meta.unit);
+    // Colour specification box with the ICC profile
+    if let Some((icc, meth)) = &icc {
+        replace_colr_with_icc(output, icc, *meth)?;
+        if *meth == 3 {
+            // Any ICC is a JPX feature; say so in the file type box
+            add_ftyp_compatibility(output, b"jpx ")?;
         }
     }
 
+    // Channel definition box marking the extra component as alpha
+    if let Some(alpha) = alpha {
+        append_to_jp2h(output, &build_cdef_box(channels, alpha))?;
+    }
+
+    // JP2 Resolution box (visible DPI for most viewers)
+    if eff.dpi_box
+        && let (Some(xdpi), Some(ydpi)) = (meta.xdpi, meta.ydpi)
+    {
+        eprintln!("  [DEBUG] Adding resolution box");
+        let _ = insert_resolution_box_jp2(output, xdpi, ydpi, meta.unit);
+    }
+
+    // Embedded TIFF metadata (--metadata copy)
+    if let Some(xmp) = &embedded.xmp {
+        append_jp2_uuid_box(output, &jp2box::XMP_UUID, xmp)?;
+    }
+    if let Some(exif) = &embedded.exif {
+        let mut payload = b"Exif\0\0".to_vec();
+        payload.extend_from_slice(exif);
+        append_jp2_uuid_box(output, &jp2box::EXIF_UUID, &payload)?;
+    }
+    if let Some(iptc) = &embedded.iptc {
+        append_jp2_uuid_box(output, &jp2box::IPTC_UUID, iptc)?;
+    }
+
     // XMP DPI (optional fallback)
-    // <<< CHANGED: use eff.xmp_dpi >>>
-    if eff.xmp_dpi {
-        if let (Some(xdpi), Some(ydpi)) = (meta.xdpi, meta.ydpi) {
-            eprintln!("  [DEBUG] Adding XMP metadata");
-            let xmp = build_xmp_with_dpi(xdpi, ydpi, meta.unit);
-            let _ = append_jp2_xmp_box(output, xmp.as_bytes());
-        }
+    if eff.xmp_dpi
+        && let (Some(xdpi), Some(ydpi)) = (meta.xdpi, meta.ydpi)
+        // A copied XMP packet is kept instead
+        && embedded.xmp.is_none()
+    {
+        eprintln!("  [DEBUG] Adding XMP metadata");
+        let xmp = build_xmp_with_dpi(xdpi, ydpi, meta.unit);
+        let _ = append_jp2_uuid_box(output, &jp2box::XMP_UUID, xmp.as_bytes());
     }
 
-    eprintln!("  [DEBUG] Conversion completed successfully");
+    // Round-trip check: decode the finished JP2 and compare every sample
+    if options.verify {
+        let verified = match &pixels {
+            Some(pixels) => {
+                let reference = ReferenceImage {
+                    width: w,
+                    height: h,
+                    channels,
+                    samples: pixels.as_reference(),
+                };
+                decoder::verify_lossless(output, n_threads, &reference)
+            }
+            None => verify_streaming(input, ifd, output, n_threads, &grid),
+        };
+        verified?;
+    }
 
-    // Krátká pauza pro stabilizaci
-    std::thread::sleep(std::time::Duration::from_millis(50));
+    eprintln!("  [DEBUG] Conversion completed successfully");
 
     Ok(())
 }
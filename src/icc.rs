@@ -0,0 +1,228 @@
+//! ICC profile header parsing (ICC.1:2010, section 7.2).
+//!
+//! Only the fixed 128-byte header is read; tags are left alone.
+
+use anyhow::{Result, bail};
+
+const HEADER_LEN: usize = 128;
+
+/// The header fields tif2jp2 cares about.
+#[derive(Clone, Copy, Debug)]
+pub struct IccHeader {
+    /// Profile size declared at offset 0.
+    pub size: u32,
+    /// Device class, e.g. `scnr` (input), `mntr` (display), `prtr` (output).
+    pub class: [u8; 4],
+    /// Data colour space, e.g. `GRAY`, `RGB `, `CMYK`.
+    pub color_space: [u8; 4],
+    /// Profile connection space, `XYZ ` or `Lab `.
+    pub pcs: [u8; 4],
+}
+
+impl IccHeader {
+    /// Colour space signature without the ICC padding, e.g. `RGB`.
+    pub fn color_space_name(&self) -> String {
+        String::from_utf8_lossy(&self.color_space)
+            .trim_end()
+            .to_string()
+    }
+}
+
+/// Parses and sanity-checks the header: length, declared size and the `acsp` signature.
+pub fn parse_header(data: &[u8]) -> Result<IccHeader> {
+    if data.len() < HEADER_LEN {
+        bail!(
+            "ICC profile is {} bytes, shorter than the 128-byte header",
+            data.len()
+        );
+    }
+    let size = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+    if (size as usize) < HEADER_LEN || size as usize > data.len() {
+        bail!(
+            "ICC header declares {} bytes but {} are present",
+            size,
+            data.len()
+        );
+    }
+    if &data[36..40] != b"acsp" {
+        bail!("ICC profile lacks the 'acsp' signature");
+    }
+    Ok(IccHeader {
+        size,
+        class: [data[12], data[13], data[14], data[15]],
+        color_space: [data[16], data[17], data[18], data[19]],
+        pcs: [data[20], data[21], data[22], data[23]],
+    })
+}
+
+/// Number of colour components a data colour space signature describes.
+pub fn color_space_channels(signature: &[u8; 4]) -> Option<u32> {
+    match signature {
+        b"GRAY" => Some(1),
+        b"RGB " | b"XYZ " | b"Lab " | b"Luv " | b"YCbr" | b"Yxy " | b"HSV " | b"HLS " | b"CMY " => {
+            Some(3)
+        }
+        b"CMYK" => Some(4),
+        // 2CLR ..= FCLR: n-colour spaces with n in hex
+        [n, b'C', b'L', b'R'] => (*n as char).to_digit(16).filter(|n| *n >= 2),
+        _ => None,
+    }
+}
+
+/// Tag signatures listed in the tag table that follows the header.
+fn tag_signatures(data: &[u8]) -> Vec<[u8; 4]> {
+    let Some(count) = data.get(128..132) else {
+        return Vec::new();
+    };
+    let count = u32::from_be_bytes([count[0], count[1], count[2], count[3]]) as usize;
+    data[132..]
+        .chunks_exact(12)
+        .take(count)
+        .map(|entry| [entry[0], entry[1], entry[2], entry[3]])
+        .collect()
+}
+
+/// JP2 'colr' method for a profile. Restricted ICC (2) is limited to Monochrome
+/// Input and Three-Component Matrix-Based Input profiles (ISO/IEC 15444-1 I.5.3.3):
+/// input or display class, XYZ connection space and TRC (+ matrix) tags only.
+/// Anything else, e.g. LUT-based or CMYK profiles, needs Any ICC (3) from JPX.
+pub fn colr_method(data: &[u8], header: &IccHeader) -> u8 {
+    let tags = tag_signatures(data);
+    let has = |sig: &[u8; 4]| tags.contains(sig);
+    let lut_based = [b"A2B0", b"A2B1", b"A2B2", b"B2A0"]
+        .iter()
+        .any(|sig| has(sig));
+    let restricted = matches!(&header.class, b"scnr" | b"mntr")
+        && &header.pcs == b"XYZ "
+        && !lut_based
+        && match &header.color_space {
+            b"GRAY" => has(b"kTRC"),
+            b"RGB " => [b"rXYZ", b"gXYZ", b"bXYZ", b"rTRC", b"gTRC", b"bTRC"]
+                .iter()
+                .all(|sig| has(sig)),
+            _ => false,
+        };
+    if restricted { 2 } else { 3 }
+}
+
+/// Validates `data` against the image colour space signature `expected`
+/// (`GRAY`, `RGB `, `CMYK`) and returns the profile trimmed to its declared size.
+pub fn validate_for_image<'a>(data: &'a [u8], expected: &[u8; 4]) -> Result<(&'a [u8], IccHeader)> {
+    let header = parse_header(data)?;
+    let image = String::from_utf8_lossy(expected).trim_end().to_string();
+    let image_channels = color_space_channels(expected).unwrap_or(0);
+    match color_space_channels(&header.color_space) {
+        Some(n) if n != image_channels => bail!(
+            "ICC profile describes {} data ({} components), the image has {} colour component(s) ({})",
+            header.color_space_name(),
+            n,
+            image_channels,
+            image
+        ),
+        None => bail!(
+            "ICC profile has an unknown colour space '{}'",
+            header.color_space_name()
+        ),
+        Some(_) if &header.color_space != expected => bail!(
+            "ICC profile is for {} data, the image is {}",
+            header.color_space_name(),
+            image
+        ),
+        Some(_) => {}
+    }
+    Ok((&data[..header.size as usize], header))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RGB_MATRIX: [&[u8; 4]; 6] = [b"rXYZ", b"gXYZ", b"bXYZ", b"rTRC", b"gTRC", b"bTRC"];
+
+    /// A header plus a tag table listing `tags` (tag data is not needed).
+    fn profile(class: &[u8; 4], space: &[u8; 4], pcs: &[u8; 4], tags: &[&[u8; 4]]) -> Vec<u8> {
+        let mut data = vec![0u8; HEADER_LEN];
+        data[12..16].copy_from_slice(class);
+        data[16..20].copy_from_slice(space);
+        data[20..24].copy_from_slice(pcs);
+        data[36..40].copy_from_slice(b"acsp");
+        data.extend_from_slice(&(tags.len() as u32).to_be_bytes());
+        for tag in tags {
+            data.extend_from_slice(*tag);
+            data.extend_from_slice(&[0; 8]);
+        }
+        let size = data.len() as u32;
+        data[0..4].copy_from_slice(&size.to_be_bytes());
+        data
+    }
+
+    fn method(data: &[u8]) -> u8 {
+        colr_method(data, &parse_header(data).unwrap())
+    }
+
+    #[test]
+    fn matrix_and_gray_trc_input_profiles_are_restricted() {
+        assert_eq!(method(&profile(b"mntr", b"RGB ", b"XYZ ", &RGB_MATRIX)), 2);
+        assert_eq!(method(&profile(b"scnr", b"RGB ", b"XYZ ", &RGB_MATRIX)), 2);
+        assert_eq!(method(&profile(b"scnr", b"GRAY", b"XYZ ", &[b"kTRC"])), 2);
+    }
+
+    #[test]
+    fn other_profiles_need_any_icc() {
+        let lut = [&RGB_MATRIX[..], &[b"A2B0"]].concat();
+        for (name, data) in [
+            ("lut", profile(b"mntr", b"RGB ", b"XYZ ", &lut)),
+            (
+                "output class",
+                profile(b"prtr", b"RGB ", b"XYZ ", &RGB_MATRIX),
+            ),
+            ("lab pcs", profile(b"mntr", b"RGB ", b"Lab ", &RGB_MATRIX)),
+            (
+                "missing trc",
+                profile(b"mntr", b"RGB ", b"XYZ ", &RGB_MATRIX[..5]),
+            ),
+            ("gray without trc", profile(b"mntr", b"GRAY", b"XYZ ", &[])),
+            ("cmyk", profile(b"scnr", b"CMYK", b"XYZ ", &[b"A2B0"])),
+        ] {
+            assert_eq!(method(&data), 3, "{}", name);
+        }
+    }
+
+    #[test]
+    fn profile_is_checked_against_the_image() {
+        let rgb = profile(b"mntr", b"RGB ", b"XYZ ", &RGB_MATRIX);
+        let mut padded = rgb.clone();
+        padded.extend_from_slice(&[0; 7]);
+        let (trimmed, header) = validate_for_image(&padded, b"RGB ").unwrap();
+        assert_eq!(trimmed, &rgb[..]);
+        assert_eq!(header.color_space_name(), "RGB");
+
+        assert!(validate_for_image(&rgb, b"GRAY").is_err());
+        assert!(validate_for_image(&rgb, b"CMYK").is_err());
+        let lab = profile(b"mntr", b"Lab ", b"XYZ ", &[]);
+        assert!(validate_for_image(&lab, b"RGB ").is_err());
+        let unknown = profile(b"mntr", b"????", b"XYZ ", &[]);
+        assert!(validate_for_image(&unknown, b"RGB ").is_err());
+    }
+
+    #[test]
+    fn corrupt_headers_are_errors() {
+        let rgb = profile(b"mntr", b"RGB ", b"XYZ ", &RGB_MATRIX);
+        // Cut anywhere, the declared size is no longer present
+        for cut in 0..rgb.len() {
+            assert!(parse_header(&rgb[..cut]).is_err(), "cut at {}", cut);
+        }
+        let mut small = rgb.clone();
+        small[0..4].copy_from_slice(&100u32.to_be_bytes());
+        assert!(parse_header(&small).is_err());
+        let mut unsigned = rgb.clone();
+        unsigned[36..40].copy_from_slice(b"xxxx");
+        assert!(parse_header(&unsigned).is_err());
+
+        // A tag table cut short or over-counted lists what is there
+        let mut overcounted = rgb.clone();
+        overcounted[128..132].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(method(&overcounted), 2);
+        assert_eq!(colr_method(&rgb[..130], &parse_header(&rgb).unwrap()), 3);
+    }
+}
@@ -1,6 +1,11 @@
 use std::path::Path;
 
 use anyhow::{Context, Result};
+use clap::ValueEnum;
+
+use crate::codestream::{self, CodestreamInfo};
+use crate::jp2box::{self, BoxDetails, Jp2Box};
+use crate::json::{JsonObject, csv_field, json_array, json_f64, json_string};
 
 #[derive(Debug, Clone)]
 pub struct Jp2Info {
@@ -8,6 +13,22 @@ pub struct Jp2Info {
     pub height: u32,
     pub components: Vec<Jp2ComponentInfo>,
     pub icc_profile_len: u32,
+    pub layers: u32,
+    /// JP2 box tree (empty for raw codestreams).
+    pub boxes: Vec<Jp2Box>,
+    /// Main and tile-part header markers.
+    pub codestream: Option<CodestreamInfo>,
+}
+
+/// Output layout of `--info`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum InfoFormat {
+    /// Indented human-readable report
+    Text,
+    /// One JSON object per file, one per line
+    Json,
+    /// Header row plus one summary row per file
+    Csv,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -25,6 +46,7 @@ pub fn print_jp2_info(path: &Path, info: &Jp2Info) {
     println!("  size: {}x{}", info.width, info.height);
     println!("  components: {}", info.components.len());
     println!("  icc_profile_len: {}", info.icc_profile_len);
+    println!("  layers: {}", info.layers);
     for (idx, component) in info.components.iter().enumerate() {
         println!(
             "  component {}: {}x{}, dx={}, dy={}, precision={}, signed={}",
@@ -37,6 +59,554 @@ pub fn print_jp2_info(path: &Path, info: &Jp2Info) {
             component.signed
         );
     }
+    if !info.boxes.is_empty() {
+        println!("  boxes:");
+        print_boxes(&info.boxes, 2);
+    }
+    if let Some(cs) = &info.codestream {
+        print_codestream(cs);
+    }
+}
+
+// --- JSON / CSV ---
+
+/// Serializes everything `--info` knows about a file as a single-line JSON object.
+pub fn jp2_info_json(path: &Path, info: &Jp2Info) -> String {
+    let components = info.components.iter().map(|c| {
+        JsonObject::new()
+            .raw("width", c.width)
+            .raw("height", c.height)
+            .raw("dx", c.dx)
+            .raw("dy", c.dy)
+            .raw("precision", c.precision)
+            .raw("signed", c.signed)
+            .finish()
+    });
+    let mut obj = JsonObject::new()
+        .str("path", &path.display().to_string())
+        .raw("width", info.width)
+        .raw("height", info.height)
+        .raw("components", json_array(components))
+        .raw("icc_profile_len", info.icc_profile_len)
+        .raw("layers", info.layers)
+        .raw("boxes", json_array(info.boxes.iter().map(box_json)));
+    obj = match &info.codestream {
+        Some(cs) => obj.raw("codestream", codestream_json(cs)),
+        None => obj.raw("codestream", "null"),
+    };
+    obj.finish()
+}
+
+fn box_json(jp2_box: &Jp2Box) -> String {
+    let mut obj = JsonObject::new()
+        .str("type", &jp2_box.kind_str())
+        .raw("offset", jp2_box.offset)
+        .raw("length", jp2_box.length)
+        .raw("extended", jp2_box.extended);
+    if let Some(details) = &jp2_box.details {
+        obj = details_json(details, obj);
+    }
+    if !jp2_box.children.is_empty() {
+        obj = obj.raw(
+            "children",
+            json_array(jp2_box.children.iter().map(box_json)),
+        );
+    }
+    obj.finish()
+}
+
+fn details_json(details: &BoxDetails, obj: JsonObject) -> JsonObject {
+    match details {
+        BoxDetails::Signature { valid } => obj.raw("valid", valid),
+        BoxDetails::FileType {
+            brand,
+            minor_version,
+            compatibility,
+        } => obj
+            .str("brand", brand)
+            .raw("minor_version", minor_version)
+            .raw(
+                "compatibility",
+                json_array(compatibility.iter().map(|cl| json_string(cl))),
+            ),
+        BoxDetails::ImageHeader {
+            width,
+            height,
+            components,
+            bpc,
+            compression,
+            unknown_colourspace,
+            ipr,
+        } => {
+            let obj = obj
+                .raw("width", width)
+                .raw("height", height)
+                .raw("components", components);
+            let obj = if *bpc == 255 {
+                obj.raw("bit_depth", "null").raw("signed", "null")
+            } else {
+                obj.raw("bit_depth", (bpc & 0x7F) + 1)
+                    .raw("signed", bpc & 0x80 != 0)
+            };
+            obj.raw("compression", compression)
+                .raw("unknown_colourspace", unknown_colourspace)
+                .raw("ipr", ipr)
+        }
+        BoxDetails::ColourSpec {
+            method,
+            precedence,
+            approx,
+            enumcs,
+            icc_len,
+        } => {
+            let obj = obj
+                .raw("method", method)
+                .raw("precedence", precedence)
+                .raw("approx", approx);
+            let obj = match enumcs {
+                Some(cs) => obj
+                    .raw("enumcs", cs)
+                    .str("enumcs_name", jp2box::enumcs_name(*cs)),
+                None => obj,
+            };
+            match icc_len {
+                Some(len) => obj.raw("icc_len", len),
+                None => obj,
+            }
+        }
+        BoxDetails::Resolution {
+            vertical_dpi,
+            horizontal_dpi,
+        } => obj
+            .raw("horizontal_dpi", json_f64(*horizontal_dpi))
+            .raw("vertical_dpi", json_f64(*vertical_dpi)),
+        BoxDetails::ChannelDef { channels } => obj.raw(
+            "channels",
+            json_array(channels.iter().map(|(channel, typ, assoc)| {
+                JsonObject::new()
+                    .raw("channel", channel)
+                    .raw("type", typ)
+                    .raw("association", assoc)
+                    .finish()
+            })),
+        ),
+        BoxDetails::Uuid { uuid } => obj
+            .str("uuid", &jp2box::format_uuid(uuid))
+            .raw("xmp", *uuid == jp2box::XMP_UUID)
+            .raw(
+                "content",
+                jp2box::uuid_name(uuid).map_or("null".into(), json_string),
+            ),
+        BoxDetails::Xml { len } => obj.raw("xml_len", len),
+    }
+}
+
+fn codestream_json(cs: &CodestreamInfo) -> String {
+    let components = cs.components.iter().map(|(bits, signed, dx, dy)| {
+        JsonObject::new()
+            .raw("bit_depth", bits)
+            .raw("signed", signed)
+            .raw("dx", dx)
+            .raw("dy", dy)
+            .finish()
+    });
+    let pair = |(w, h): (u32, u32)| format!("[{},{}]", w, h);
+    JsonObject::new()
+        .raw("offset", cs.offset)
+        .raw("rsiz", cs.rsiz)
+        .raw("width", cs.width)
+        .raw("height", cs.height)
+        .raw("image_offset", pair(cs.image_offset))
+        .raw("tile_size", pair(cs.tile_size))
+        .raw("tile_offset", pair(cs.tile_offset))
+        .raw("tiles", pair(cs.tiles))
+        .raw("tile_count", cs.tile_count())
+        .raw("components", json_array(components))
+        .str("progression", codestream::progression_name(cs.progression))
+        .raw("layers", cs.layers)
+        .raw("mct", cs.mct)
+        .raw("levels", cs.levels)
+        .raw("code_block", pair(cs.code_block))
+        .raw("code_block_style", cs.code_block_style)
+        .raw(
+            "code_block_style_flags",
+            json_array(
+                codestream::code_block_style_names(cs.code_block_style)
+                    .into_iter()
+                    .map(json_string),
+            ),
+        )
+        .str("transform", transform_name(cs.transform))
+        .raw(
+            "precincts",
+            json_array(cs.precincts.iter().map(|&p| pair(p))),
+        )
+        .str("quantization", quantization_name(cs.quantization))
+        .raw("guard_bits", cs.guard_bits)
+        .raw("sop_signalled", cs.sop_signalled)
+        .raw("sop_found", cs.sop_found)
+        .raw("eph_signalled", cs.eph_signalled)
+        .raw("eph_found", cs.eph_found)
+        .raw("tlm_segments", cs.tlm_segments)
+        .raw("plm_segments", cs.plm_segments)
+        .raw("plt_segments", cs.plt_segments)
+        .raw("ppm_segments", cs.ppm_segments)
+        .raw("coc_segments", cs.coc_segments)
+        .raw("qcc_segments", cs.qcc_segments)
+        .raw("poc_segments", cs.poc_segments)
+        .raw("tile_parts", cs.tile_parts)
+        .raw("tile_parts_per_tile", cs.tile_parts_per_tile)
+        .raw("tiles_seen", cs.tiles_seen)
+        .raw("tile_header_overrides", cs.tile_header_overrides)
+        .raw("eoc", cs.eoc)
+        .raw(
+            "comments",
+            json_array(cs.comments.iter().map(|c| json_string(c))),
+        )
+        .finish()
+}
+
+/// Column names of `--info --format csv`.
+pub const INFO_CSV_HEADER: &str = "path,width,height,components,precision,signed,icc_profile_len,\
+brand,colr_method,enumcs,horizontal_dpi,vertical_dpi,xmp,progression,layers,mct,levels,\
+code_block,code_block_style,transform,precincts,quantization,tile_size,tile_count,tile_parts,\
+tlm,plt,sop,eph,eoc";
+
+/// One CSV summary row per file; nested box and marker details stay in the JSON form.
+pub fn jp2_info_csv_row(path: &Path, info: &Jp2Info) -> String {
+    let find = |kind: &[u8; 4]| find_box(&info.boxes, kind).and_then(|b| b.details.as_ref());
+    let (brand, colr_method, enumcs) = (
+        match find(b"ftyp") {
+            Some(BoxDetails::FileType { brand, .. }) => brand.clone(),
+            _ => String::new(),
+        },
+        match find(b"colr") {
+            Some(BoxDetails::ColourSpec { method, .. }) => method.to_string(),
+            _ => String::new(),
+        },
+        match find(b"colr") {
+            Some(BoxDetails::ColourSpec {
+                enumcs: Some(cs), ..
+            }) => cs.to_string(),
+            _ => String::new(),
+        },
+    );
+    // Capture resolution wins over the default display resolution
+    let (hdpi, vdpi) = match find(b"resc").or_else(|| find(b"resd")) {
+        Some(BoxDetails::Resolution {
+            horizontal_dpi,
+            vertical_dpi,
+        }) => (
+            format!("{:.2}", horizontal_dpi),
+            format!("{:.2}", vertical_dpi),
+        ),
+        _ => (String::new(), String::new()),
+    };
+    let xmp = has_xmp(&info.boxes);
+    let first = info.components.first();
+
+    let mut fields = vec![
+        path.display().to_string(),
+        info.width.to_string(),
+        info.height.to_string(),
+        info.components.len().to_string(),
+        first.map(|c| c.precision.to_string()).unwrap_or_default(),
+        first.map(|c| c.signed.to_string()).unwrap_or_default(),
+        info.icc_profile_len.to_string(),
+        brand,
+        colr_method,
+        enumcs,
+        hdpi,
+        vdpi,
+        xmp.to_string(),
+    ];
+    match &info.codestream {
+        Some(cs) => fields.extend([
+            codestream::progression_name(cs.progression).to_string(),
+            cs.layers.to_string(),
+            cs.mct.to_string(),
+            cs.levels.to_string(),
+            format!("{}x{}", cs.code_block.0, cs.code_block.1),
+            codestream::code_block_style_names(cs.code_block_style).join("|"),
+            transform_name(cs.transform).to_string(),
+            cs.precincts
+                .iter()
+                .map(|(w, h)| format!("{}x{}", w, h))
+                .collect::<Vec<_>>()
+                .join("|"),
+            quantization_name(cs.quantization).to_string(),
+            format!("{}x{}", cs.tile_size.0, cs.tile_size.1),
+            cs.tile_count().to_string(),
+            cs.tile_parts.to_string(),
+            cs.tlm_segments.to_string(),
+            cs.plt_segments.to_string(),
+            cs.sop_found.to_string(),
+            cs.eph_found.to_string(),
+            cs.eoc.to_string(),
+        ]),
+        None => fields.extend(std::iter::repeat_n(String::new(), 17)),
+    }
+    fields
+        .iter()
+        .map(|f| csv_field(f))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn find_box<'a>(boxes: &'a [Jp2Box], kind: &[u8; 4]) -> Option<&'a Jp2Box> {
+    boxes.iter().find_map(|b| {
+        if &b.kind == kind {
+            Some(b)
+        } else {
+            find_box(&b.children, kind)
+        }
+    })
+}
+
+fn has_xmp(boxes: &[Jp2Box]) -> bool {
+    boxes.iter().any(|b| {
+        matches!(b.details, Some(BoxDetails::Uuid { uuid }) if uuid == jp2box::XMP_UUID)
+            || has_xmp(&b.children)
+    })
+}
+
+fn transform_name(transform: u8) -> &'static str {
+    match transform {
+        0 => "9/7 irreversible",
+        1 => "5/3 reversible",
+        _ => "unknown",
+    }
+}
+
+fn quantization_name(style: u8) -> &'static str {
+    match style {
+        0 => "no quantization",
+        1 => "scalar derived",
+        2 => "scalar expounded",
+        _ => "unknown",
+    }
+}
+
+fn print_codestream(cs: &CodestreamInfo) {
+    let yes_no = |b: bool| if b { "yes" } else { "no" };
+    println!("  codestream (SOC at {}):", cs.offset);
+    println!(
+        "    SIZ: {}x{} at ({}, {}), Rsiz=0x{:04X}, {} component(s): {}",
+        cs.width,
+        cs.height,
+        cs.image_offset.0,
+        cs.image_offset.1,
+        cs.rsiz,
+        cs.components.len(),
+        cs.components
+            .iter()
+            .map(|(bits, signed, dx, dy)| format!(
+                "{}{} {}x{}",
+                bits,
+                if *signed { "s" } else { "u" },
+                dx,
+                dy
+            ))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    println!(
+        "    tiles: {}x{} ({} x {} = {} tile(s)), tile offset ({}, {})",
+        cs.tile_size.0,
+        cs.tile_size.1,
+        cs.tiles.0,
+        cs.tiles.1,
+        cs.tile_count(),
+        cs.tile_offset.0,
+        cs.tile_offset.1
+    );
+    println!(
+        "    COD: order={}, layers={}, MCT={}, levels={} ({} resolutions), code-block {}x{}",
+        codestream::progression_name(cs.progression),
+        cs.layers,
+        cs.mct,
+        cs.levels,
+        cs.levels as u32 + 1,
+        cs.code_block.0,
+        cs.code_block.1
+    );
+    let styles = codestream::code_block_style_names(cs.code_block_style);
+    println!(
+        "    code-block style: 0x{:02X} [{}]",
+        cs.code_block_style,
+        styles.join(", ")
+    );
+    println!("    transform: {}", transform_name(cs.transform));
+    if cs.precincts.is_empty() {
+        println!("    precincts: default (maximal)");
+    } else {
+        println!(
+            "    precincts (lowest resolution first): {}",
+            cs.precincts
+                .iter()
+                .map(|(w, h)| format!("{}x{}", w, h))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    println!(
+        "    QCD: {}, guard bits={}",
+        quantization_name(cs.quantization),
+        cs.guard_bits
+    );
+    println!(
+        "    SOP: signalled={}, present={}; EPH: signalled={}, present={}",
+        yes_no(cs.sop_signalled),
+        yes_no(cs.sop_found),
+        yes_no(cs.eph_signalled),
+        yes_no(cs.eph_found)
+    );
+    println!(
+        "    markers: TLM={}, PLM={}, PLT={}, PPM={}, COC={}, QCC={}, POC={}",
+        cs.tlm_segments,
+        cs.plm_segments,
+        cs.plt_segments,
+        cs.ppm_segments,
+        cs.coc_segments,
+        cs.qcc_segments,
+        cs.poc_segments
+    );
+    println!(
+        "    tile-parts: {} total, up to {} per tile, {} of {} tile(s) present, {} with header overrides, EOC={}",
+        cs.tile_parts,
+        cs.tile_parts_per_tile,
+        cs.tiles_seen,
+        cs.tile_count(),
+        cs.tile_header_overrides,
+        yes_no(cs.eoc)
+    );
+    for comment in &cs.comments {
+        println!("    COM: {}", comment);
+    }
+}
+
+fn print_boxes(boxes: &[Jp2Box], depth: usize) {
+    for jp2_box in boxes {
+        let mut line = format!(
+            "{:indent$}@{} len={}{} '{}'",
+            "",
+            jp2_box.offset,
+            jp2_box.length,
+            if jp2_box.extended { " (XLBox)" } else { "" },
+            jp2_box.kind_str(),
+            indent = depth * 2
+        );
+        if let Some(details) = &jp2_box.details {
+            line.push(' ');
+            line.push_str(&describe_box(details));
+        } else if &jp2_box.kind == b"jp2c" {
+            line.push_str(" codestream");
+        }
+        println!("{}", line);
+        print_boxes(&jp2_box.children, depth + 1);
+    }
+}
+
+fn describe_box(details: &BoxDetails) -> String {
+    match details {
+        BoxDetails::Signature { valid } => {
+            format!("signature {}", if *valid { "ok" } else { "INVALID" })
+        }
+        BoxDetails::FileType {
+            brand,
+            minor_version,
+            compatibility,
+        } => format!(
+            "brand='{}' minor={} compatible=[{}]",
+            brand,
+            minor_version,
+            compatibility
+                .iter()
+                .map(|cl| format!("'{}'", cl))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        BoxDetails::ImageHeader {
+            width,
+            height,
+            components,
+            bpc,
+            compression,
+            unknown_colourspace,
+            ipr,
+        } => {
+            let depth = if *bpc == 255 {
+                "varies (bpcc)".to_string()
+            } else {
+                format!(
+                    "{}-bit {}",
+                    (bpc & 0x7F) + 1,
+                    if bpc & 0x80 != 0 {
+                        "signed"
+                    } else {
+                        "unsigned"
+                    }
+                )
+            };
+            format!(
+                "{}x{}, {} component(s), {}, C={}, UnkC={}, IPR={}",
+                width, height, components, depth, compression, unknown_colourspace, ipr
+            )
+        }
+        BoxDetails::ColourSpec {
+            method,
+            precedence,
+            approx,
+            enumcs,
+            icc_len,
+        } => {
+            let spec = match (method, enumcs, icc_len) {
+                (1, Some(cs), _) => {
+                    format!("enumerated, EnumCS={} ({})", cs, jp2box::enumcs_name(*cs))
+                }
+                (2, _, Some(len)) => format!("restricted ICC, {} bytes", len),
+                (3, _, Some(len)) => format!("any ICC, {} bytes", len),
+                _ => "unknown method".to_string(),
+            };
+            format!(
+                "method={} {}, precedence={}, approx={}",
+                method, spec, precedence, approx
+            )
+        }
+        BoxDetails::Resolution {
+            vertical_dpi,
+            horizontal_dpi,
+        } => format!(
+            "{:.2} x {:.2} DPI (horizontal x vertical)",
+            horizontal_dpi, vertical_dpi
+        ),
+        BoxDetails::ChannelDef { channels } => channels
+            .iter()
+            .map(|(channel, typ, assoc)| {
+                let typ = match typ {
+                    0 => "colour".to_string(),
+                    1 => "opacity".to_string(),
+                    2 => "premultiplied opacity".to_string(),
+                    other => format!("type {}", other),
+                };
+                let assoc = match assoc {
+                    0 => "whole image".to_string(),
+                    65535 => "unassociated".to_string(),
+                    n => format!("colour {}", n),
+                };
+                format!("#{} {} -> {}", channel, typ, assoc)
+            })
+            .collect::<Vec<_>>()
+            .join(", "),
+        BoxDetails::Uuid { uuid } => format!(
+            "{}{}",
+            jp2box::format_uuid(uuid),
+            jp2box::uuid_name(uuid)
+                .map(|name| format!(" ({})", name))
+                .unwrap_or_default()
+        ),
+        BoxDetails::Xml { len } => format!("{} bytes of XML", len),
+    }
 }
 
 pub fn openjpeg_threads(threads: usize) -> Result<i32> {
@@ -0,0 +1,90 @@
+//! `--jobs`: several files converted at once, limited by a job count and a
+//! shared memory budget.
+
+use std::sync::{Condvar, Mutex};
+
+/// Admission control for concurrent conversions. The main thread reserves a
+/// slot and the file's estimated memory before handing the file to the pool,
+/// so pool threads never block on it.
+pub struct Scheduler {
+    jobs: usize,
+    budget: Option<u64>,
+    /// Running jobs and their reserved bytes.
+    state: Mutex<(usize, u64)>,
+    finished: Condvar,
+}
+
+impl Scheduler {
+    pub fn new(jobs: usize, budget: Option<u64>) -> Self {
+        Self {
+            jobs: jobs.max(1),
+            budget,
+            state: Mutex::new((0, 0)),
+            finished: Condvar::new(),
+        }
+    }
+
+    /// Blocks until a job slot is free and `bytes` fit in the budget, and
+    /// returns the amount reserved. A file larger than the whole budget waits
+    /// until nothing else runs.
+    pub fn acquire(&self, bytes: u64) -> u64 {
+        let bytes = self.budget.map_or(bytes, |budget| bytes.min(budget));
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            let (running, in_use) = *state;
+            let fits = self
+                .budget
+                .is_none_or(|budget| running == 0 || in_use + bytes <= budget);
+            if running < self.jobs && fits {
+                *state = (running + 1, in_use + bytes);
+                return bytes;
+            }
+            state = self.finished.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+
+    pub fn release(&self, bytes: u64) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.0 -= 1;
+        state.1 -= bytes;
+        self.finished.notify_all();
+    }
+}
+
+/// `--jobs 0` means one job per core.
+pub fn resolve_jobs(jobs: usize) -> usize {
+    if jobs == 0 { cores() } else { jobs }
+}
+
+/// OpenJPEG threads per file: an explicit `--threads` is kept, otherwise the
+/// cores are shared out between the concurrent jobs.
+pub fn threads_per_job(threads: usize, jobs: usize) -> usize {
+    if threads == 0 {
+        (cores() / jobs.max(1)).max(1)
+    } else {
+        threads
+    }
+}
+
+fn cores() -> usize {
+    std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+}
+
+/// Default `--jobs-memory`: three quarters of the physical memory, when known.
+pub fn default_budget() -> Option<u64> {
+    physical_memory().map(|bytes| bytes / 4 * 3)
+}
+
+#[cfg(unix)]
+fn physical_memory() -> Option<u64> {
+    let pages = unsafe { libc::sysconf(libc::_SC_PHYS_PAGES) };
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    (pages > 0 && page_size > 0).then(|| pages as u64 * page_size as u64)
+}
+
+#[cfg(not(unix))]
+fn physical_memory() -> Option<u64> {
+    None
+}
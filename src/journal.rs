@@ -0,0 +1,350 @@
+//! `--journal`: one JSON line per conversion with the input and output hashes,
+//! the options and the outcome, so `--resume` can tell outputs that completed
+//! from ones an interrupted run left half-written.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+
+use crate::json::{JsonObject, json_f64, json_string};
+use crate::report::{FileResult, Outcome};
+
+/// What a journal line says about one output.
+#[derive(Debug, Clone)]
+struct Entry {
+    input: String,
+    ifd: usize,
+    options: String,
+    input_hash: String,
+    output_hash: Option<String>,
+    status: String,
+}
+
+pub struct Journal {
+    path: PathBuf,
+    file: Mutex<File>,
+    /// Last entry per output path, from the lines already in the file.
+    previous: HashMap<String, Entry>,
+}
+
+impl Journal {
+    /// Opens `path` for appending and loads its earlier entries. Lines that do
+    /// not parse (e.g. cut off by a kill) are ignored.
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut previous = HashMap::new();
+        if path.exists() {
+            let text = fs::read_to_string(path)
+                .with_context(|| format!("Reading journal {}", path.display()))?;
+            for fields in text.lines().filter_map(parse_line) {
+                if let Some((output, entry)) = entry_from(&fields) {
+                    previous.insert(output, entry);
+                }
+            }
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Opening journal {}", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+            previous,
+        })
+    }
+
+    /// True when the journal has an `ok` entry for `out` made from the same
+    /// input page and options, and neither file has changed since.
+    pub fn completed(&self, input: &Path, ifd: usize, out: &Path, options: &str) -> bool {
+        let Some(entry) = self.previous.get(&key(out)) else {
+            return false;
+        };
+        entry.status == Outcome::Ok.as_str()
+            && entry.input == key(input)
+            && entry.ifd == ifd
+            && entry.options == options
+            && entry.output_hash.is_some()
+            && file_hash(out).ok() == entry.output_hash
+            && file_hash(input).ok().as_ref() == Some(&entry.input_hash)
+    }
+
+    /// Appends the outcome of one conversion. The output is hashed only when
+    /// it completed.
+    pub fn record(&self, ifd: usize, options: &str, result: &FileResult) -> Result<()> {
+        let input_hash = file_hash(&result.input)?;
+        let output_hash = match (&result.output, result.outcome) {
+            (Some(out), Outcome::Ok) => Some(file_hash(out)?),
+            _ => None,
+        };
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let opt_str = |v: Option<&str>| v.map_or("null".into(), json_string);
+        let mut line = JsonObject::new()
+            .str("input", &key(&result.input))
+            .raw("ifd", ifd)
+            .raw(
+                "output",
+                opt_str(result.output.as_deref().map(key).as_deref()),
+            )
+            .str("options", options)
+            .str("input_hash", &input_hash)
+            .raw("output_hash", opt_str(output_hash.as_deref()))
+            .str("status", result.outcome.as_str())
+            .raw("seconds", json_f64(result.seconds))
+            .raw("time", time)
+            .raw("message", opt_str(result.message.as_deref()))
+            .finish();
+        line.push('\n');
+        // One write per line, so concurrent jobs never interleave
+        self.file
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .write_all(line.as_bytes())
+            .with_context(|| format!("Writing journal {}", self.path.display()))
+    }
+}
+
+/// The options as the journal records them: `name=value` pairs of the settings
+/// that shape the output, plus the hash of the `--icc` profile, so that editing
+/// the profile in place is noticed.
+pub fn options_key(mut parts: Vec<String>, icc: Option<&Path>) -> Result<String> {
+    if let Some(icc) = icc {
+        parts.push(format!("icc={}", file_hash(icc)?));
+    }
+    Ok(parts.join(" "))
+}
+
+fn key(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+fn entry_from(fields: &HashMap<String, Option<String>>) -> Option<(String, Entry)> {
+    let field = |name: &str| fields.get(name).cloned().flatten();
+    let entry = Entry {
+        input: field("input")?,
+        ifd: field("ifd")?.parse().ok()?,
+        options: field("options")?,
+        input_hash: field("input_hash")?,
+        output_hash: field("output_hash"),
+        status: field("status")?,
+    };
+    Some((field("output")?, entry))
+}
+
+/// `sha256:<hex>` of a file's contents.
+fn file_hash(path: &Path) -> Result<String> {
+    let mut file = File::open(path).with_context(|| format!("Hashing {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 1 << 20];
+    loop {
+        let n = file
+            .read(&mut buf)
+            .with_context(|| format!("Hashing {}", path.display()))?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    let hex: String = hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    Ok(format!("sha256:{}", hex))
+}
+
+// --- Reading journal lines ---
+
+/// Parses one flat JSON object as written by `Journal::record`: string,
+/// number and null values only. Numbers are kept as their text.
+fn parse_line(line: &str) -> Option<HashMap<String, Option<String>>> {
+    let mut chars = line.trim().chars().peekable();
+    let mut fields = HashMap::new();
+    if chars.next()? != '{' {
+        return None;
+    }
+    loop {
+        match chars.next()? {
+            '}' if fields.is_empty() => break,
+            '"' => {}
+            _ => return None,
+        }
+        let name = parse_string(&mut chars)?;
+        if chars.next()? != ':' {
+            return None;
+        }
+        let value = match chars.peek()? {
+            '"' => {
+                chars.next();
+                Some(parse_string(&mut chars)?)
+            }
+            _ => {
+                let mut text = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c == ',' || c == '}' {
+                        break;
+                    }
+                    text.push(c);
+                    chars.next();
+                }
+                (text != "null").then_some(text)
+            }
+        };
+        fields.insert(name, value);
+        match chars.next()? {
+            ',' => continue,
+            '}' => break,
+            _ => return None,
+        }
+    }
+    chars.next().is_none().then_some(fields)
+}
+
+/// Reads a JSON string body after its opening quote.
+fn parse_string(chars: &mut impl Iterator<Item = char>) -> Option<String> {
+    let mut out = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some(out),
+            '\\' => out.push(match chars.next()? {
+                'n' => '\n',
+                'r' => '\r',
+                't' => '\t',
+                'u' => {
+                    let hex: String = chars.by_ref().take(4).collect();
+                    char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?
+                }
+                c => c,
+            }),
+            c => out.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fresh directory under the system temp dir, removed by the caller.
+    fn scratch(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("tif2jp2-journal-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn ok_result(input: &Path, output: &Path) -> FileResult {
+        let mut result = FileResult::new(input, Some(output), Outcome::Ok);
+        result.seconds = 1.5;
+        result
+    }
+
+    #[test]
+    fn recorded_line_round_trips() {
+        let dir = scratch("round-trip");
+        let (input, output) = (dir.join("in.tif"), dir.join("out.jp2"));
+        fs::write(&input, b"tiff").unwrap();
+        fs::write(&output, b"jp2").unwrap();
+        let journal_path = dir.join("run.jsonl");
+
+        let journal = Journal::open(&journal_path).unwrap();
+        journal
+            .record(2, "opts", &ok_result(&input, &output))
+            .unwrap();
+        drop(journal);
+
+        let journal = Journal::open(&journal_path).unwrap();
+        let entry = &journal.previous[&key(&output)];
+        assert_eq!(entry.input, key(&input));
+        assert_eq!(entry.ifd, 2);
+        assert_eq!(entry.status, "ok");
+        assert_eq!(entry.output_hash, Some(file_hash(&output).unwrap()));
+        assert!(journal.completed(&input, 2, &output, "opts"));
+        assert!(!journal.completed(&input, 1, &output, "opts"));
+        assert!(!journal.completed(&input, 2, &output, "other opts"));
+
+        // A changed output is no longer completed
+        fs::write(&output, b"jp").unwrap();
+        assert!(!journal.completed(&input, 2, &output, "opts"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn escaped_strings_round_trip() {
+        let path = "dir \"x\"\\sub\n\ttab\u{1}é.tif";
+        let line = JsonObject::new()
+            .str("input", path)
+            .raw("ifd", 0)
+            .raw("output_hash", "null")
+            .finish();
+        let fields = parse_line(&line).unwrap();
+        assert_eq!(fields["input"].as_deref(), Some(path));
+        assert_eq!(fields["ifd"].as_deref(), Some("0"));
+        assert_eq!(fields["output_hash"], None);
+    }
+
+    #[test]
+    fn malformed_lines_are_rejected() {
+        let line = JsonObject::new()
+            .str("input", "a.tif")
+            .str("status", "ok")
+            .finish();
+        for cut in 0..line.len() {
+            assert!(parse_line(&line[..cut]).is_none(), "{}", &line[..cut]);
+        }
+        assert!(parse_line(&format!("{}x", line)).is_none());
+        assert!(parse_line("[1,2]").is_none());
+        assert!(parse_line(r#"{"a":"\u12"}"#).is_none());
+        assert!(parse_line("{}").is_some());
+    }
+
+    #[test]
+    fn truncated_last_line_is_ignored() {
+        let dir = scratch("truncated");
+        let (input, output) = (dir.join("in.tif"), dir.join("out.jp2"));
+        fs::write(&input, b"tiff").unwrap();
+        fs::write(&output, b"jp2").unwrap();
+        let journal_path = dir.join("run.jsonl");
+        let journal = Journal::open(&journal_path).unwrap();
+        journal
+            .record(0, "opts", &ok_result(&input, &output))
+            .unwrap();
+        drop(journal);
+
+        // A kill in the middle of the next line
+        let mut text = fs::read_to_string(&journal_path).unwrap();
+        let complete = text.len();
+        text.push_str(&text.clone()[..complete / 2]);
+        fs::write(&journal_path, text).unwrap();
+
+        let journal = Journal::open(&journal_path).unwrap();
+        assert_eq!(journal.previous.len(), 1);
+        assert!(journal.completed(&input, 0, &output, "opts"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn icc_contents_are_part_of_the_options() {
+        let dir = scratch("icc");
+        let icc = dir.join("profile.icc");
+        fs::write(&icc, b"one").unwrap();
+        let parts = || vec!["tile=1024x1024".to_string(), "sop=true".to_string()];
+        let before = options_key(parts(), Some(&icc)).unwrap();
+        fs::write(&icc, b"two").unwrap();
+        assert_ne!(before, options_key(parts(), Some(&icc)).unwrap());
+        assert!(before.starts_with("tile=1024x1024 sop=true icc="));
+        assert_eq!(
+            options_key(parts(), None).unwrap(),
+            "tile=1024x1024 sop=true"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
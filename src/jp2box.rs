@@ -0,0 +1,419 @@
+//! JP2 box structure (ISO/IEC 15444-1 Annex I) as read back for `--info`.
+
+use std::io::{Read, Seek, SeekFrom};
+
+use anyhow::{Result, bail};
+
+/// Boxes whose payload is a sequence of boxes.
+const SUPERBOXES: [&[u8; 4]; 4] = [b"jp2h", b"res ", b"uinf", b"asoc"];
+
+/// Leaf payload bytes kept for field decoding (profiles and metadata are only measured).
+const PEEK_LEN: u64 = 4096;
+
+/// UUID of the Adobe XMP 'uuid' box.
+pub const XMP_UUID: [u8; 16] = [
+    0xBE, 0x7A, 0xCF, 0xCB, 0x97, 0xA9, 0x42, 0xE8, 0x9C, 0x71, 0x99, 0x94, 0x91, 0xE3, 0xAF, 0xAC,
+];
+
+/// UUID of the EXIF 'uuid' box ("JpgTiffExif->JP2").
+pub const EXIF_UUID: [u8; 16] = *b"JpgTiffExif->JP2";
+
+/// UUID of the IPTC-IIM 'uuid' box (as written by Adobe and Kakadu).
+pub const IPTC_UUID: [u8; 16] = [
+    0x33, 0xC7, 0xA4, 0xD2, 0xB8, 0x1D, 0x47, 0x23, 0xA0, 0xBA, 0xF1, 0xA3, 0xE0, 0x97, 0xAD, 0x38,
+];
+
+#[derive(Debug, Clone)]
+pub struct Jp2Box {
+    pub offset: u64,
+    /// Total length including the header.
+    pub length: u64,
+    /// The length came from an XLBox (LBox = 1).
+    pub extended: bool,
+    pub kind: [u8; 4],
+    pub details: Option<BoxDetails>,
+    pub children: Vec<Jp2Box>,
+}
+
+impl Jp2Box {
+    pub fn kind_str(&self) -> String {
+        self.kind
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '?'
+                }
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum BoxDetails {
+    Signature {
+        valid: bool,
+    },
+    FileType {
+        brand: String,
+        minor_version: u32,
+        compatibility: Vec<String>,
+    },
+    ImageHeader {
+        width: u32,
+        height: u32,
+        components: u16,
+        /// Raw BPC byte: bit depth - 1, high bit = signed, 255 = varies ('bpcc').
+        bpc: u8,
+        compression: u8,
+        unknown_colourspace: u8,
+        ipr: u8,
+    },
+    ColourSpec {
+        method: u8,
+        precedence: i8,
+        approx: u8,
+        enumcs: Option<u32>,
+        icc_len: Option<u64>,
+    },
+    Resolution {
+        vertical_dpi: f64,
+        horizontal_dpi: f64,
+    },
+    ChannelDef {
+        /// (channel, type, association)
+        channels: Vec<(u16, u16, u16)>,
+    },
+    Uuid {
+        uuid: [u8; 16],
+    },
+    Xml {
+        len: u64,
+    },
+}
+
+/// Reads the top-level box tree of a JP2 file, descending into superboxes.
+pub fn read_box_tree<R: Read + Seek>(file: &mut R, file_len: u64) -> Result<Vec<Jp2Box>> {
+    read_boxes(file, 0, file_len)
+}
+
+/// Full payload of `jp2_box` (after the box header).
+pub fn read_payload<R: Read + Seek>(file: &mut R, jp2_box: &Jp2Box) -> Result<Vec<u8>> {
+    let header_len = if jp2_box.extended { 16 } else { 8 };
+    file.seek(SeekFrom::Start(jp2_box.offset + header_len))?;
+    let mut payload = vec![0u8; (jp2_box.length - header_len) as usize];
+    file.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+/// XMP packet from the first top-level XMP 'uuid' box.
+pub fn read_xmp<R: Read + Seek>(file: &mut R, boxes: &[Jp2Box]) -> Option<String> {
+    let xmp_box = boxes.iter().find(
+        |jp2_box| matches!(jp2_box.details, Some(BoxDetails::Uuid { uuid }) if uuid == XMP_UUID),
+    )?;
+    let payload = read_payload(file, xmp_box).ok()?;
+    Some(String::from_utf8_lossy(&payload[16..]).into_owned())
+}
+
+fn read_boxes<R: Read + Seek>(file: &mut R, start: u64, end: u64) -> Result<Vec<Jp2Box>> {
+    let mut boxes = Vec::new();
+    let mut pos = start;
+    while pos + 8 <= end {
+        file.seek(SeekFrom::Start(pos))?;
+        let mut header = [0u8; 8];
+        file.read_exact(&mut header)?;
+        let lbox = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as u64;
+        let kind = [header[4], header[5], header[6], header[7]];
+        let (header_len, length) = match lbox {
+            0 => (8, end - pos),
+            1 => {
+                let mut xl = [0u8; 8];
+                file.read_exact(&mut xl)?;
+                (16, u64::from_be_bytes(xl))
+            }
+            n => (8, n),
+        };
+        if length < header_len || length > end - pos {
+            bail!(
+                "Box '{}' at offset {} claims {} bytes, but only {} remain",
+                String::from_utf8_lossy(&kind),
+                pos,
+                length,
+                end - pos
+            );
+        }
+
+        let payload_start = pos + header_len;
+        let payload_len = length - header_len;
+        let mut jp2_box = Jp2Box {
+            offset: pos,
+            length,
+            extended: lbox == 1,
+            kind,
+            details: None,
+            children: Vec::new(),
+        };
+        if SUPERBOXES.contains(&&kind) {
+            jp2_box.children = read_boxes(file, payload_start, pos + length)?;
+        } else if &kind == b"jp2c" {
+            // Codestream: markers are analysed separately (codestream.rs)
+        } else {
+            let mut peek = vec![0u8; payload_len.min(PEEK_LEN) as usize];
+            file.read_exact(&mut peek)?;
+            jp2_box.details = decode_details(&kind, &peek, payload_len);
+        }
+        boxes.push(jp2_box);
+        pos += length;
+    }
+    Ok(boxes)
+}
+
+fn decode_details(kind: &[u8; 4], p: &[u8], payload_len: u64) -> Option<BoxDetails> {
+    let u16_at = |o: usize| Some(u16::from_be_bytes([*p.get(o)?, *p.get(o + 1)?]));
+    let u32_at = |o: usize| {
+        Some(u32::from_be_bytes([
+            *p.get(o)?,
+            *p.get(o + 1)?,
+            *p.get(o + 2)?,
+            *p.get(o + 3)?,
+        ]))
+    };
+    let fourcc = |b: &[u8]| String::from_utf8_lossy(b).into_owned();
+    Some(match kind {
+        b"jP  " => BoxDetails::Signature {
+            valid: p == [0x0D, 0x0A, 0x87, 0x0A],
+        },
+        b"ftyp" => BoxDetails::FileType {
+            brand: fourcc(p.get(0..4)?),
+            minor_version: u32_at(4)?,
+            compatibility: p.get(8..)?.chunks_exact(4).map(fourcc).collect(),
+        },
+        b"ihdr" => BoxDetails::ImageHeader {
+            height: u32_at(0)?,
+            width: u32_at(4)?,
+            components: u16_at(8)?,
+            bpc: *p.get(10)?,
+            compression: *p.get(11)?,
+            unknown_colourspace: *p.get(12)?,
+            ipr: *p.get(13)?,
+        },
+        b"colr" => {
+            let method = *p.first()?;
+            BoxDetails::ColourSpec {
+                method,
+                precedence: *p.get(1)? as i8,
+                approx: *p.get(2)?,
+                enumcs: if method == 1 { u32_at(3) } else { None },
+                icc_len: matches!(method, 2 | 3).then(|| payload_len.saturating_sub(3)),
+            }
+        }
+        b"resc" | b"resd" => {
+            // VR = VRcN / VRcD * 10^VRcE pixels per metre (likewise HR)
+            let ppm = |n: u16, d: u16, e: i8| n as f64 / d.max(1) as f64 * 10f64.powi(e as i32);
+            let vertical = ppm(u16_at(0)?, u16_at(2)?, *p.get(8)? as i8);
+            let horizontal = ppm(u16_at(4)?, u16_at(6)?, *p.get(9)? as i8);
+            BoxDetails::Resolution {
+                vertical_dpi: vertical * 0.0254,
+                horizontal_dpi: horizontal * 0.0254,
+            }
+        }
+        b"cdef" => {
+            let n = u16_at(0)? as usize;
+            BoxDetails::ChannelDef {
+                channels: (0..n)
+                    .map(|i| Some((u16_at(2 + i * 6)?, u16_at(4 + i * 6)?, u16_at(6 + i * 6)?)))
+                    .collect::<Option<_>>()?,
+            }
+        }
+        b"uuid" => BoxDetails::Uuid {
+            uuid: p.get(0..16)?.try_into().ok()?,
+        },
+        b"xml " => BoxDetails::Xml { len: payload_len },
+        _ => return None,
+    })
+}
+
+/// Human name of an enumerated colour space (EnumCS) value.
+pub fn enumcs_name(enumcs: u32) -> &'static str {
+    match enumcs {
+        0 => "bi-level",
+        1 => "YCbCr(1)",
+        3 => "YCbCr(2)",
+        4 => "YCbCr(3)",
+        9 => "PhotoYCC",
+        11 => "CMY",
+        12 => "CMYK",
+        13 => "YCCK",
+        14 => "CIELab",
+        15 => "bi-level(2)",
+        16 => "sRGB",
+        17 => "greyscale",
+        18 => "sYCC",
+        19 => "CIEJab",
+        20 => "e-sRGB",
+        21 => "ROMM-RGB",
+        22 => "YPbPr(1125/60)",
+        23 => "YPbPr(1250/50)",
+        24 => "e-sYCC",
+        _ => "unknown",
+    }
+}
+
+/// Well-known content of a 'uuid' box.
+pub fn uuid_name(uuid: &[u8; 16]) -> Option<&'static str> {
+    match *uuid {
+        XMP_UUID => Some("XMP"),
+        EXIF_UUID => Some("EXIF"),
+        IPTC_UUID => Some("IPTC"),
+        _ => None,
+    }
+}
+
+pub fn format_uuid(uuid: &[u8; 16]) -> String {
+    let hex: String = uuid.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn boxed(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut out = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+        out.extend_from_slice(kind);
+        out.extend_from_slice(payload);
+        out
+    }
+
+    /// A box with LBox = 1 and the length in XLBox.
+    fn xl_boxed(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut out = 1u32.to_be_bytes().to_vec();
+        out.extend_from_slice(kind);
+        out.extend_from_slice(&((payload.len() + 16) as u64).to_be_bytes());
+        out.extend_from_slice(payload);
+        out
+    }
+
+    fn tree(bytes: &[u8]) -> Result<Vec<Jp2Box>> {
+        read_box_tree(&mut Cursor::new(bytes), bytes.len() as u64)
+    }
+
+    fn resolution(n: u16, d: u16, e: i8) -> Vec<u8> {
+        let mut p = Vec::new();
+        for _ in 0..2 {
+            p.extend_from_slice(&n.to_be_bytes());
+            p.extend_from_slice(&d.to_be_bytes());
+        }
+        p.extend_from_slice(&[e as u8, e as u8]);
+        p
+    }
+
+    #[test]
+    fn superboxes_and_xlbox_nest() {
+        let ihdr = [0, 0, 0, 2, 0, 0, 0, 3, 0, 1, 7, 7, 0, 0];
+        let res = boxed(b"res ", &boxed(b"resc", &resolution(1, 1, 0)));
+        let jp2h = boxed(b"jp2h", &[boxed(b"ihdr", &ihdr), res].concat());
+        let xmp = xl_boxed(b"uuid", &[&XMP_UUID[..], b"<x/>"].concat());
+        let file = [
+            boxed(b"jP  ", &[0x0D, 0x0A, 0x87, 0x0A]),
+            jp2h.clone(),
+            xmp.clone(),
+            boxed(b"jp2c", &[0xFF, 0x4F]),
+        ]
+        .concat();
+
+        let boxes = tree(&file).unwrap();
+        let kinds: Vec<String> = boxes.iter().map(Jp2Box::kind_str).collect();
+        assert_eq!(kinds, ["jP  ", "jp2h", "uuid", "jp2c"]);
+        assert!(matches!(
+            boxes[0].details,
+            Some(BoxDetails::Signature { valid: true })
+        ));
+
+        let jp2h_box = &boxes[1];
+        assert_eq!(jp2h_box.length, jp2h.len() as u64);
+        assert_eq!(jp2h_box.children.len(), 2);
+        assert!(matches!(
+            jp2h_box.children[0].details,
+            Some(BoxDetails::ImageHeader {
+                width: 3,
+                height: 2,
+                components: 1,
+                bpc: 7,
+                ..
+            })
+        ));
+        let res_box = &jp2h_box.children[1];
+        assert_eq!(res_box.kind_str(), "res ");
+        assert_eq!(res_box.children[0].kind_str(), "resc");
+        assert_eq!(res_box.children[0].offset, 12 + 8 + 22 + 8);
+
+        let uuid = &boxes[2];
+        assert!(uuid.extended);
+        assert_eq!(uuid.length, xmp.len() as u64);
+        assert!(matches!(uuid.details, Some(BoxDetails::Uuid { uuid }) if uuid == XMP_UUID));
+        assert_eq!(boxes[3].offset + boxes[3].length, file.len() as u64);
+    }
+
+    #[test]
+    fn negative_resolution_exponent() {
+        // 28346 * 10^-1 pixels per metre = 72 dpi
+        let p = resolution(28346, 1, -1);
+        let Some(BoxDetails::Resolution {
+            vertical_dpi,
+            horizontal_dpi,
+        }) = decode_details(b"resd", &p, p.len() as u64)
+        else {
+            panic!("resd not decoded");
+        };
+        assert!((vertical_dpi - 2834.6 * 0.0254).abs() < 1e-9);
+        assert_eq!(vertical_dpi, horizontal_dpi);
+        assert!((vertical_dpi - 72.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn corrupt_lengths_are_errors() {
+        let signature = boxed(b"jP  ", &[0x0D, 0x0A, 0x87, 0x0A]);
+        let mut too_long = boxed(b"xml ", b"<a/>");
+        too_long[3] += 1;
+        let mut too_short = boxed(b"xml ", b"<a/>");
+        too_short[0..4].copy_from_slice(&4u32.to_be_bytes());
+        let mut xl_huge = xl_boxed(b"uuid", &[0; 16]);
+        xl_huge[8..16].copy_from_slice(&u64::MAX.to_be_bytes());
+        let nested_too_long = boxed(b"jp2h", &too_long);
+
+        for (name, bad) in [
+            ("too-long", too_long),
+            ("too-short", too_short),
+            ("xl-huge", xl_huge),
+            ("nested", nested_too_long),
+        ] {
+            let file = [signature.clone(), bad].concat();
+            assert!(tree(&file).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn truncated_file_does_not_panic() {
+        let jp2h = boxed(b"jp2h", &boxed(b"colr", &[1, 0, 0, 0, 0, 0, 16]));
+        let file = [
+            boxed(b"jP  ", &[0x0D, 0x0A, 0x87, 0x0A]),
+            jp2h,
+            xl_boxed(b"uuid", &[&EXIF_UUID[..], b"II*\0"].concat()),
+        ]
+        .concat();
+        for cut in 0..file.len() {
+            // Cut-off boxes are errors, cut-off field bytes just lack details
+            let _ = tree(&file[..cut]);
+        }
+    }
+}
@@ -0,0 +1,81 @@
+//! Hand-written JSON and CSV output shared by `--info`, `--report` and
+//! `--journal`.
+
+use std::fmt::Display;
+
+// --- JSON ---
+
+/// Minimal JSON object writer; keys keep insertion order.
+pub struct JsonObject {
+    out: String,
+}
+
+impl JsonObject {
+    pub fn new() -> Self {
+        Self {
+            out: String::from("{"),
+        }
+    }
+
+    /// Adds a value that is already valid JSON (number, bool, array, object).
+    pub fn raw(mut self, key: &str, value: impl Display) -> Self {
+        if self.out.len() > 1 {
+            self.out.push(',');
+        }
+        self.out.push_str(&json_string(key));
+        self.out.push(':');
+        self.out.push_str(&value.to_string());
+        self
+    }
+
+    pub fn str(self, key: &str, value: &str) -> Self {
+        self.raw(key, json_string(value))
+    }
+
+    pub fn finish(mut self) -> String {
+        self.out.push('}');
+        self.out
+    }
+}
+
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+pub fn json_array(items: impl IntoIterator<Item = String>) -> String {
+    format!("[{}]", items.into_iter().collect::<Vec<_>>().join(","))
+}
+
+/// JSON has no NaN/Infinity; those become null.
+pub fn json_f64(v: f64) -> String {
+    if v.is_finite() {
+        format!("{}", v)
+    } else {
+        "null".to_string()
+    }
+}
+
+// --- CSV ---
+
+/// Quotes a field when it contains a separator, quote or line break (RFC 4180).
+pub fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
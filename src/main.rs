@@ -1,17 +1,40 @@
+mod atomic;
+mod codestream;
 mod decoder;
 mod encoder;
+mod icc;
 mod info;
+mod jobs;
+mod journal;
+mod jp2box;
+mod json;
+mod metadata;
+mod png;
+mod pnm;
+mod profile;
+mod report;
+mod worker;
+mod xmp;
 
 use std::{
+    collections::HashMap,
+    ffi::OsString,
     fs,
     io::Write,
     path::{Path, PathBuf},
-    time::Instant,
+    process::ExitCode,
+    sync::Mutex,
+    time::{Duration, Instant},
 };
 
 use anyhow::{Context, Result, anyhow};
-use clap::{ArgAction, Parser};
-use encoder::{Effective, EncodeOptions};
+use clap::{ArgAction, Parser, ValueEnum};
+use decoder::{DecodeOptions, DecodeRegion, OutputFormat};
+use encoder::{Effective, EncodeOptions, Quality, TiffPages};
+use info::InfoFormat;
+use metadata::MetadataMode;
+use profile::Profile;
+use report::{FileResult, Outcome, RunSummary};
 use walkdir::WalkDir;
 
 /// Tiny logger with verbosity levels (0 = errors only, 1 = info)
@@ -52,10 +75,65 @@ pub struct Args {
     #[arg(long, action = ArgAction::SetTrue, conflicts_with = "info")]
     pub decode: bool,
 
+    /// With --decode: keep original sample values (e.g. 12-bit stays 0..4095) instead of rescaling
+    #[arg(long = "native-precision", action = ArgAction::SetTrue, requires = "decode")]
+    pub native_precision: bool,
+
+    /// With --decode: output format [default: from the -o extension, else tiff]
+    #[arg(
+        long = "output-format",
+        value_enum,
+        value_name = "FORMAT",
+        requires = "decode"
+    )]
+    pub output_format: Option<OutputFormat>,
+
+    /// With --decode: discard the N highest resolution levels (N=1 halves width and height)
+    #[arg(long, default_value_t = 0u32, value_name = "N", requires = "decode")]
+    pub reduce: u32,
+
+    /// With --decode: decode only a rectangle, in full-resolution pixels
+    #[arg(long, value_name = "X,Y,W,H", value_parser = parse_region, requires = "decode")]
+    pub region: Option<DecodeRegion>,
+
+    /// With --decode: decode only tile N (0-based raster index, see --info)
+    #[arg(
+        long = "tile-index",
+        value_name = "N",
+        requires = "decode",
+        conflicts_with = "region"
+    )]
+    pub tile_index: Option<u32>,
+
     /// Print JPEG2000 header information and exit
     #[arg(long, action = ArgAction::SetTrue, conflicts_with = "decode")]
     pub info: bool,
 
+    /// Output format of --info (json: one object per line; csv: header plus one row per file)
+    #[arg(long, value_enum, default_value_t = InfoFormat::Text, requires = "info")]
+    pub format: InfoFormat,
+
+    /// Check existing JP2 files against an archival profile and report PASS/WARN/FAIL per requirement
+    #[arg(long, value_enum, value_name = "PROFILE", conflicts_with_all = ["decode", "info"])]
+    pub validate: Option<Profile>,
+
+    /// Write per-file status, timing, sizes and compression ratio to a .json or .csv file
+    #[arg(
+        long,
+        value_name = "PATH",
+        value_parser = report::parse_report_path,
+        conflicts_with_all = ["info", "validate"]
+    )]
+    pub report: Option<PathBuf>,
+
+    /// Append one JSON line per converted file (input/output hashes, options, status) to PATH
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["info", "validate"])]
+    pub journal: Option<PathBuf>,
+
+    /// With --journal: skip files the journal records as completed and unchanged, redo all others
+    #[arg(long, action = ArgAction::SetTrue, requires = "journal", conflicts_with = "force")]
+    pub resume: bool,
+
     /// Recursively traverse the input directory
     #[arg(long)]
     pub recursive: bool,
@@ -76,10 +154,34 @@ pub struct Args {
     #[arg(long)]
     pub force: bool,
 
-    /// OpenJPEG threads (0 = auto = all cores)
+    /// OpenJPEG threads per file (0 = auto = all cores, shared out between --jobs)
     #[arg(long, default_value_t = 0usize, value_name = "N")]
     pub threads: usize,
 
+    /// Convert N files at once (0 = one per core)
+    #[arg(long, default_value_t = 1usize, value_name = "N", conflicts_with_all = ["info", "validate"])]
+    pub jobs: usize,
+
+    /// Convert every file in a separate worker process, so a crash fails only that file
+    #[arg(long, action = ArgAction::SetTrue, conflicts_with_all = ["info", "validate"])]
+    pub isolate: bool,
+
+    /// With --isolate: kill a worker that runs longer than SECS seconds and fail its file
+    #[arg(long, value_name = "SECS", requires = "isolate")]
+    pub timeout: Option<u64>,
+
+    /// Worker process: IFD of the input to encode
+    #[arg(long = "worker-ifd", hide = true, default_value_t = 0usize)]
+    pub worker_ifd: usize,
+
+    /// Worker process: output path for the single input (set by --isolate)
+    #[arg(long = "worker-output", hide = true)]
+    pub worker_output: Option<PathBuf>,
+
+    /// Memory shared by concurrent --jobs, e.g. 16G; a file starts once its estimated need fits [default: 3/4 of RAM]
+    #[arg(long = "jobs-memory", value_name = "SIZE", value_parser = parse_memory_size)]
+    pub jobs_memory: Option<u64>,
+
     /// Path to ICC profile (overrides ICC detected in TIFF)
     #[arg(long, value_name = "PATH")]
     pub icc: Option<PathBuf>,
@@ -88,11 +190,64 @@ pub struct Args {
     #[arg(long, default_value = "RPCL", value_name = "ORDER")]
     pub order: String,
 
+    /// Decode the written JP2 and check every sample against the TIFF (fails the file on mismatch)
+    #[arg(long, action = ArgAction::SetTrue, conflicts_with_all = ["rate", "bpp", "psnr"])]
+    pub verify: bool,
+
+    /// Lossy 9/7: target compression ratio, e.g. 20 for 20:1
+    #[arg(long, value_name = "RATIO", conflicts_with_all = ["bpp", "psnr", "archival_master_ndk"])]
+    pub rate: Option<f32>,
+
+    /// Lossy 9/7: target bits per pixel (all components together), e.g. 2.0
+    #[arg(long, value_name = "BPP", conflicts_with_all = ["rate", "psnr", "archival_master_ndk"])]
+    pub bpp: Option<f32>,
+
+    /// Lossy 9/7: target PSNR in dB (fixed-quality allocation), e.g. 45
+    #[arg(long, value_name = "DB", conflicts_with_all = ["rate", "bpp", "archival_master_ndk"])]
+    pub psnr: Option<f32>,
+
+    /// Quality layers as decreasing ratios, e.g. 80,40,20,10,5,0 (trailing 0 = lossless last layer)
+    #[arg(
+        long,
+        value_name = "R1,R2,...",
+        value_delimiter = ',',
+        num_args = 1..,
+        conflicts_with_all = ["rate", "bpp", "psnr", "archival_master_ndk"]
+    )]
+    pub layers: Vec<f32>,
+
+    /// Encode only page N of a multi-page TIFF (1-based; thumbnails are not counted)
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..),
+        conflicts_with_all = ["all_pages", "decode", "info"]
+    )]
+    pub page: Option<u32>,
+
+    /// Encode every page of a multi-page TIFF as NAME_p0001.jp2, NAME_p0002.jp2, ...
+    #[arg(long = "all-pages", action = ArgAction::SetTrue, conflicts_with_all = ["decode", "info"])]
+    pub all_pages: bool,
+
+    /// Memory budget per file, e.g. 512M or 4G; larger images are encoded tile row by tile row
+    #[arg(long = "max-memory", value_name = "SIZE", value_parser = parse_memory_size)]
+    pub max_memory: Option<u64>,
+
     /// Archival master NDK preset (alias: --archival). Forces RPCL, 4096x4096 tiles, 64x64 blocks,
     /// levels=6, SOP/EPH on, precincts on (256..128), tile-parts R, reversible MCT on, TLM on.
     #[arg(long = "archival-master-ndk", alias = "archival", action = ArgAction::SetTrue)]
     pub archival_master_ndk: bool,
 
+    /// Embedded TIFF metadata: none drops it, copy carries XMP, IPTC and EXIF into JP2 'uuid' boxes
+    #[arg(
+        long,
+        value_enum,
+        value_name = "MODE",
+        default_value_t = MetadataMode::None,
+        conflicts_with_all = ["archival_master_ndk", "decode", "info"]
+    )]
+    pub metadata: MetadataMode,
+
     /// Write DPI into JP2 'res' box [default: on]
     #[arg(long = "dpi-box", action = ArgAction::SetTrue, overrides_with = "dpi_box_off")]
     pub dpi_box_on: bool,
@@ -181,6 +336,8 @@ impl Args {
     fn operation(&self) -> Operation {
         if self.info {
             Operation::Info
+        } else if self.validate.is_some() {
+            Operation::Validate
         } else if self.decode {
             Operation::Decode
         } else {
@@ -188,16 +345,194 @@ impl Args {
         }
     }
 
-    fn encode_options(&self) -> EncodeOptions {
-        EncodeOptions {
+    fn encode_options(&self) -> Result<EncodeOptions> {
+        Ok(EncodeOptions {
             tile: self.tile.clone(),
             block: self.block.clone(),
             levels: self.levels.clone(),
-            threads: self.threads,
+            threads: self.threads_per_file(),
             icc: self.icc.clone(),
             order: self.order.clone(),
+            verify: self.verify,
+            quality: self.quality()?,
+            max_memory: self.max_memory,
+            metadata: self.metadata,
             toggles: self.effective(),
+        })
+    }
+
+    fn quality(&self) -> Result<Quality> {
+        let positive = |name: &str, value: f32| {
+            if value.is_finite() && value > 0.0 {
+                Ok(value)
+            } else {
+                Err(anyhow!(
+                    "--{} must be a positive number (got {})",
+                    name,
+                    value
+                ))
+            }
+        };
+        if !self.layers.is_empty() {
+            return parse_layers(&self.layers);
+        }
+        Ok(match (self.rate, self.bpp, self.psnr) {
+            (Some(rate), _, _) => Quality::Ratio(positive("rate", rate)?),
+            (_, Some(bpp), _) => Quality::Bpp(positive("bpp", bpp)?),
+            (_, _, Some(psnr)) => Quality::Psnr(positive("psnr", psnr)?),
+            (None, None, None) => Quality::Lossless,
+        })
+    }
+
+    fn decode_options(&self) -> Result<DecodeOptions> {
+        Ok(DecodeOptions {
+            threads: info::openjpeg_threads(self.threads_per_file())?,
+            native_precision: self.native_precision,
+            reduce: self.reduce,
+            region: self.region,
+            tile: self.tile_index,
+            format: self.decode_format(),
+        })
+    }
+
+    /// Arguments of an `--isolate` worker besides its input and output: the
+    /// resolved encode or decode options only. Inputs, outputs and batch options (--jobs,
+    /// --journal, --report, ...) stay with the parent.
+    fn worker_args(&self, operation: Operation) -> Vec<OsString> {
+        let mut args: Vec<OsString> = Vec::new();
+        let mut opt = |name: &str, value: OsString| {
+            args.push(name.into());
+            args.push(value);
+        };
+        opt("--threads", self.threads_per_file().to_string().into());
+        if operation == Operation::Decode {
+            opt("--output-format", value_name(self.decode_format()).into());
+            opt("--reduce", self.reduce.to_string().into());
+            if let Some(r) = self.region {
+                let region = format!("{},{},{},{}", r.x, r.y, r.width, r.height);
+                opt("--region", region.into());
+            }
+            if let Some(tile) = self.tile_index {
+                opt("--tile-index", tile.to_string().into());
+            }
+            args.push("--decode".into());
+            if self.native_precision {
+                args.push("--native-precision".into());
+            }
+            return args;
+        }
+
+        opt("--tile", self.tile.clone().into());
+        opt("--block", self.block.clone().into());
+        opt("--levels", self.levels.clone().into());
+        opt("--order", self.order.clone().into());
+        opt("--metadata", value_name(self.metadata).into());
+        if let Some(icc) = &self.icc {
+            opt("--icc", icc.into());
+        }
+        if let Some(max_memory) = self.max_memory {
+            opt("--max-memory", max_memory.to_string().into());
+        }
+        if !self.layers.is_empty() {
+            let layers: Vec<String> = self.layers.iter().map(f32::to_string).collect();
+            opt("--layers", layers.join(",").into());
+        } else if let Some(rate) = self.rate {
+            opt("--rate", rate.to_string().into());
+        } else if let Some(bpp) = self.bpp {
+            opt("--bpp", bpp.to_string().into());
+        } else if let Some(psnr) = self.psnr {
+            opt("--psnr", psnr.to_string().into());
+        }
+        if self.verify {
+            args.push("--verify".into());
+        }
+        let e = self.effective();
+        for (name, on) in [
+            ("avx2", e.avx2),
+            ("dpi-box", e.dpi_box),
+            ("xmp-dpi", e.xmp_dpi),
+            ("tp-r", e.tp_r),
+            ("precincts", e.precincts),
+            ("sop", e.sop),
+            ("eph", e.eph),
+            ("mct", e.mct),
+            ("tlm", e.tlm),
+            ("plt", e.plt),
+            ("bypass", e.bypass),
+        ] {
+            let prefix = if on { "--" } else { "--no-" };
+            args.push(format!("{}{}", prefix, name).into());
+        }
+        args
+    }
+
+    /// The options a journal line is matched on: only the resolved settings
+    /// that shape the output bytes. Threads, --max-memory, --avx2 and --verify
+    /// change how an output is made, not what it contains.
+    fn journal_key(&self, operation: Operation) -> Result<String> {
+        let mut parts: Vec<String> = Vec::new();
+        if operation == Operation::Decode {
+            parts.push(format!(
+                "output-format={}",
+                value_name(self.decode_format())
+            ));
+            parts.push(format!("reduce={}", self.reduce));
+            if let Some(r) = self.region {
+                parts.push(format!("region={},{},{},{}", r.x, r.y, r.width, r.height));
+            }
+            if let Some(tile) = self.tile_index {
+                parts.push(format!("tile-index={}", tile));
+            }
+            parts.push(format!("native-precision={}", self.native_precision));
+            return journal::options_key(parts, None);
+        }
+
+        parts.push(format!("tile={}", self.tile));
+        parts.push(format!("block={}", self.block));
+        parts.push(format!("levels={}", self.levels));
+        parts.push(format!("order={}", self.order));
+        parts.push(match self.quality()? {
+            Quality::Lossless => "quality=lossless".to_string(),
+            Quality::Ratio(rate) => format!("rate={}", rate),
+            Quality::Bpp(bpp) => format!("bpp={}", bpp),
+            Quality::Psnr(psnr) => format!("psnr={}", psnr),
+            Quality::Layers(rates) => {
+                let rates: Vec<String> = rates.iter().map(f32::to_string).collect();
+                format!("layers={}", rates.join(","))
+            }
+        });
+        parts.push(format!("metadata={}", value_name(self.metadata)));
+        let e = self.effective();
+        for (name, on) in [
+            ("dpi-box", e.dpi_box),
+            ("xmp-dpi", e.xmp_dpi),
+            ("tp-r", e.tp_r),
+            ("precincts", e.precincts),
+            ("sop", e.sop),
+            ("eph", e.eph),
+            ("mct", e.mct),
+            ("tlm", e.tlm),
+            ("plt", e.plt),
+            ("bypass", e.bypass),
+        ] {
+            parts.push(format!("{}={}", name, on));
         }
+        journal::options_key(parts, self.icc.as_deref())
+    }
+
+    fn threads_per_file(&self) -> usize {
+        jobs::threads_per_job(self.threads, jobs::resolve_jobs(self.jobs))
+    }
+
+    /// `--output-format`, else the format named by the `-o` extension, else TIFF.
+    fn decode_format(&self) -> OutputFormat {
+        self.output_format
+            .or_else(|| {
+                self.output
+                    .as_deref()
+                    .and_then(OutputFormat::from_extension)
+            })
+            .unwrap_or(OutputFormat::Tiff)
     }
 
     fn effective(&self) -> Effective {
@@ -228,18 +563,97 @@ impl Args {
     }
 }
 
+/// Validates a `--layers` ladder: ratios must strictly decrease and 0 may only come last.
+fn parse_layers(rates: &[f32]) -> Result<Quality> {
+    for (idx, &rate) in rates.iter().enumerate() {
+        let last = idx + 1 == rates.len();
+        if !rate.is_finite() || rate < 0.0 || (rate == 0.0 && !last) {
+            return Err(anyhow!(
+                "--layers: invalid ratio {} (use positive ratios, 0 only as the last layer)",
+                rate
+            ));
+        }
+        if idx > 0 && rate != 0.0 && rate >= rates[idx - 1] {
+            return Err(anyhow!(
+                "--layers: ratios must decrease from layer to layer ({} after {})",
+                rate,
+                rates[idx - 1]
+            ));
+        }
+    }
+    Ok(Quality::Layers(rates.to_vec()))
+}
+
+/// Parses a byte count with an optional binary suffix (K, M, G, T; "B"/"iB" accepted).
+fn parse_memory_size(s: &str) -> Result<u64> {
+    let t = s.trim().to_ascii_uppercase();
+    let t = t
+        .strip_suffix("IB")
+        .or_else(|| t.strip_suffix('B'))
+        .unwrap_or(&t);
+    let (digits, shift) = match t.chars().last() {
+        Some('K') => (&t[..t.len() - 1], 10),
+        Some('M') => (&t[..t.len() - 1], 20),
+        Some('G') => (&t[..t.len() - 1], 30),
+        Some('T') => (&t[..t.len() - 1], 40),
+        _ => (t, 0),
+    };
+    let value: f64 = digits
+        .trim()
+        .parse()
+        .map_err(|_| anyhow!("invalid size '{}' (use e.g. 512M, 4G)", s))?;
+    if !value.is_finite() || value <= 0.0 {
+        return Err(anyhow!("size must be positive (got '{}')", s));
+    }
+    Ok((value * (1u64 << shift) as f64) as u64)
+}
+
+/// The command-line spelling of a `--flag` value.
+fn value_name(value: impl ValueEnum) -> String {
+    value
+        .to_possible_value()
+        .map(|v| v.get_name().to_string())
+        .unwrap_or_default()
+}
+
+/// Parses `--region x,y,w,h`.
+fn parse_region(s: &str) -> Result<DecodeRegion> {
+    let parts = s
+        .split(',')
+        .map(|part| part.trim().parse::<u32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| anyhow!("expected x,y,w,h as non-negative integers (got '{}')", s))?;
+    let [x, y, width, height] = parts[..] else {
+        return Err(anyhow!("expected 4 values x,y,w,h (got '{}')", s));
+    };
+    if width == 0 || height == 0 {
+        return Err(anyhow!(
+            "region width and height must be positive (got '{}')",
+            s
+        ));
+    }
+    Ok(DecodeRegion {
+        x,
+        y,
+        width,
+        height,
+    })
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 enum Operation {
     Encode,
     Decode,
     Info,
+    Validate,
 }
 
 fn apply_archival_master_ndk_defaults(args: &mut Args) {
-    args.tile = "4096x4096".into();
-    args.block = "64x64".into();
-    args.levels = "6".into();
-    args.order = "RPCL".into();
+    let ndk = &profile::NDK;
+    args.tile = format!("{}x{}", ndk.tile.0, ndk.tile.1);
+    args.block = format!("{}x{}", ndk.block.0, ndk.block.1);
+    args.levels = ndk.resolutions.to_string();
+    args.order = ndk.order.into();
 
     args.dpi_box_on = true;
     args.dpi_box_off = false;
@@ -265,7 +679,7 @@ fn apply_archival_master_ndk_defaults(args: &mut Args) {
     args.bypass_off = false;
 }
 
-fn main() -> Result<()> {
+fn main() -> Result<ExitCode> {
     let mut args = Args::parse();
     let operation = args.operation();
 
@@ -276,90 +690,415 @@ fn main() -> Result<()> {
         );
     }
 
+    if let Some(out) = &args.worker_output {
+        return run_worker(&args, operation, &args.input, out);
+    }
+
     let log = Log::new(args.verbose);
     log.v1(format!("Input:  {}", args.input.display()));
     if let Some(out) = &args.output {
         log.v1(format!("Output: {}", out.display()));
     }
 
-    if let Some(out_dir) = &args.output {
-        if out_dir.is_dir() || (!out_dir.exists() && args.input.is_dir()) {
-            fs::create_dir_all(out_dir).context("Creating output directory")?;
-        }
-    }
-
     let inputs = collect_inputs(&args.input, args.recursive, operation)?;
     log.v1(format!("Found {} input file(s).", inputs.len()));
     if inputs.is_empty() {
         return Err(anyhow!("No input files found"));
     }
 
-    let encode_options = args.encode_options();
+    // Every output path, page suffixes included, is worked out before anything
+    // is written, so that a collision stops the run up front
+    let mut summary = RunSummary::default();
+    let mut planned = Vec::new();
+    if matches!(operation, Operation::Encode | Operation::Decode) {
+        for (idx, input) in inputs.iter().enumerate() {
+            // TIFF pages to encode; decode has a single target
+            let targets = if operation == Operation::Encode {
+                match select_pages(&args, input, &log) {
+                    Ok(targets) => targets,
+                    Err(e) => {
+                        eprintln!("ERR {} - Error: {}", input.display(), e);
+                        let mut failed = FileResult::new(input, None, Outcome::Failed);
+                        failed.message = Some(format!("{:#}", e));
+                        summary.record(failed);
+                        continue;
+                    }
+                }
+            } else {
+                vec![PageTarget {
+                    ifd: 0,
+                    page: None,
+                    suffix: None,
+                }]
+            };
+
+            for target in targets {
+                let mut out = derive_output_path(&args, input, operation)?;
+                if let Some(suffix) = &target.suffix {
+                    out = with_stem_suffix(&out, suffix);
+                }
+                planned.push(Task {
+                    number: idx + 1,
+                    input,
+                    ifd: target.ifd,
+                    page: target.page,
+                    out,
+                });
+            }
+        }
+        check_output_collisions(&planned)?;
+    }
+
+    if let Some(out_dir) = &args.output
+        && (out_dir.is_dir() || (!out_dir.exists() && args.input.is_dir()))
+    {
+        fs::create_dir_all(out_dir).context("Creating output directory")?;
+    }
+
+    let encode_options = args.encode_options()?;
+    if encode_options.verify && !encode_options.quality.is_lossless() {
+        return Err(anyhow!(
+            "--verify needs a lossless encode (end --layers with 0 or drop the lossy target)"
+        ));
+    }
+    let decode_options = args.decode_options()?;
+    let journal = args
+        .journal
+        .as_deref()
+        .map(journal::Journal::open)
+        .transpose()?;
+    let journal_options = match operation {
+        Operation::Encode | Operation::Decode if journal.is_some() => {
+            args.journal_key(operation)?
+        }
+        _ => String::new(),
+    };
+    let mut validation_failures = 0usize;
     for (idx, input) in inputs.iter().enumerate() {
         if operation == Operation::Info {
             let info = decoder::read_info(input, info::openjpeg_threads(args.threads)?)?;
-            info::print_jp2_info(input, &info);
+            match args.format {
+                InfoFormat::Text => info::print_jp2_info(input, &info),
+                InfoFormat::Json => println!("{}", info::jp2_info_json(input, &info)),
+                InfoFormat::Csv => {
+                    if idx == 0 {
+                        println!("{}", info::INFO_CSV_HEADER);
+                    }
+                    println!("{}", info::jp2_info_csv_row(input, &info));
+                }
+            }
             continue;
         }
-
-        let out = derive_output_path(&args, input, operation)?;
-        if let Some(parent) = out.parent() {
-            if !parent.exists() {
-                fs::create_dir_all(parent).context("Creating output subdirectory")?;
+        if let Some(profile) = args.validate {
+            let spec = profile.spec();
+            match decoder::read_info(input, info::openjpeg_threads(args.threads)?) {
+                Ok(info) => {
+                    let checks = profile::validate(&info, spec);
+                    profile::print_report(input, spec, &checks);
+                    if profile::overall(&checks) == profile::Status::Fail {
+                        validation_failures += 1;
+                    }
+                }
+                Err(e) => {
+                    eprintln!("ERR {} - Error: {}", input.display(), e);
+                    validation_failures += 1;
+                }
             }
         }
+    }
 
-        if out.exists() && !args.force {
+    let mut tasks = Vec::new();
+    for task in planned {
+        let Task {
+            input,
+            ifd,
+            ref out,
+            ..
+        } = task;
+        if let Some(parent) = out.parent()
+            && !parent.exists()
+        {
+            fs::create_dir_all(parent).context("Creating output subdirectory")?;
+        }
+
+        if args.resume
+            && let Some(journal) = &journal
+        {
+            if journal.completed(input, ifd, out, &journal_options) {
+                eprintln!("Skipping (completed, per journal): {}", out.display());
+                let mut skipped =
+                    FileResult::new(input, Some(out), Outcome::Skipped).for_page(task.page);
+                skipped.message = Some("completed in an earlier run".to_string());
+                summary.record(skipped);
+                continue;
+            }
+            if out.exists() {
+                eprintln!("Redoing (not completed, per journal): {}", out.display());
+            }
+        } else if out.exists() && !args.force {
             eprintln!("Skipping (exists): {}", out.display());
+            summary.record(FileResult::new(input, Some(out), Outcome::Skipped).for_page(task.page));
             continue;
         }
+        tasks.push(task);
+    }
 
-        eprintln!(
-            "({}/{}) -> Processing: {} -> {}",
-            idx + 1,
-            inputs.len(),
-            input.display(),
-            out.display()
-        );
-        let t0 = Instant::now();
-
-        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| match operation {
-            Operation::Encode => encoder::encode_tiff_to_jp2(input, &out, &encode_options),
-            Operation::Decode => {
-                decoder::decode_to_tiff(input, &out, info::openjpeg_threads(args.threads)?)
+    if !tasks.is_empty() {
+        let jobs = jobs::resolve_jobs(args.jobs);
+        let scheduler = jobs::Scheduler::new(jobs, args.jobs_memory.or_else(jobs::default_budget));
+        // At least one pool thread per core so rayon work inside a conversion
+        // keeps all cores busy; the scheduler limits the number of files
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(jobs.max(rayon::current_num_threads()))
+            .build()
+            .context("Starting the conversion thread pool")?;
+        let finished = Mutex::new(Vec::with_capacity(tasks.len()));
+        let timeout = args.timeout.map(Duration::from_secs);
+        let worker_args = args.worker_args(operation);
+        let run = |task: &Task| match operation {
+            _ if args.isolate => {
+                worker::run(&worker_args, task.input, task.ifd, &task.out, timeout)
             }
-            Operation::Info => unreachable!("info mode is handled before output derivation"),
-        }));
-
-        match result {
-            Ok(Ok(())) => {
-                let dt = t0.elapsed();
-                eprintln!("OK {} -> {} ({:.2?})", input.display(), out.display(), dt);
+            Operation::Encode => {
+                encoder::encode_tiff_to_jp2(task.input, task.ifd, &task.out, &encode_options)
             }
-            Ok(Err(e)) => {
-                eprintln!("ERR {} - Error: {}", input.display(), e);
+            Operation::Decode => decoder::decode_to_file(task.input, &task.out, &decode_options),
+            Operation::Info | Operation::Validate => {
+                unreachable!("info and validate modes are handled before output derivation")
+            }
+        };
+        let estimate = |task: &Task| match operation {
+            Operation::Encode => {
+                encoder::estimate_encode_memory(task.input, task.ifd, &encode_options)
             }
-            Err(panic) => {
-                if let Some(s) = panic.downcast_ref::<&str>() {
-                    eprintln!("ERR {} - Panic during conversion: {}", input.display(), s);
-                } else if let Some(s) = panic.downcast_ref::<String>() {
-                    eprintln!("ERR {} - Panic during conversion: {}", input.display(), s);
+            _ => decoder::estimate_decode_memory(task.input, &decode_options),
+        };
+        pool.in_place_scope_fifo(|scope| {
+            for task in &tasks {
+                // A single job runs alone anyway; skip reading the headers twice
+                let needed = if jobs > 1 {
+                    estimate(task).unwrap_or(0)
                 } else {
-                    eprintln!(
-                        "ERR {} - Panic during conversion: <unknown reason>",
-                        input.display()
-                    );
-                }
+                    0
+                };
+                let reserved = scheduler.acquire(needed);
+                let (scheduler, finished, run) = (&scheduler, &finished, &run);
+                let (journal, journal_options) = (&journal, &journal_options);
+                let total = inputs.len();
+                scope.spawn_fifo(move |_| {
+                    let result = run_task(task, total, run);
+                    scheduler.release(reserved);
+                    if let Some(journal) = journal
+                        && let Err(e) = journal.record(task.ifd, journal_options, &result)
+                    {
+                        eprintln!("WARN journal: {:#}", e);
+                    }
+                    finished
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .push(result);
+                });
             }
+        });
+        for result in finished.into_inner().unwrap_or_else(|e| e.into_inner()) {
+            summary.record(result);
         }
+        summary
+            .results
+            .sort_by(|a, b| (&a.input, &a.output).cmp(&(&b.input, &b.output)));
+    }
 
-        let _ = std::io::stderr().flush();
-        let _ = std::io::stdout().flush();
-        std::thread::sleep(std::time::Duration::from_millis(100));
+    if let Some(profile) = args.validate {
+        if validation_failures > 0 {
+            eprintln!(
+                "{} of {} file(s) failed {} validation",
+                validation_failures,
+                inputs.len(),
+                profile.spec().name
+            );
+            return Ok(ExitCode::from(report::EXIT_FAILED));
+        }
+        eprintln!(
+            "All {} file(s) conform to {}",
+            inputs.len(),
+            profile.spec().name
+        );
+        return Ok(ExitCode::SUCCESS);
+    }
+    if operation == Operation::Info {
+        return Ok(ExitCode::SUCCESS);
     }
 
-    eprintln!("All files processed successfully!");
-    Ok(())
+    summary.print();
+    if let Some(path) = &args.report {
+        summary.write_report(path)?;
+        eprintln!("Report written to {}", path.display());
+    }
+    Ok(summary.exit_code())
+}
+
+/// One conversion: a TIFF page to encode or a JPEG 2000 file to decode.
+struct Task<'a> {
+    /// 1-based position of the input, for progress lines.
+    number: usize,
+    input: &'a Path,
+    ifd: usize,
+    /// 1-based page number when the input has more than one page.
+    page: Option<usize>,
+    out: PathBuf,
+}
+
+/// Runs one conversion and turns its result (or panic) into a `FileResult`.
+fn run_task(task: &Task, total: usize, run: impl Fn(&Task) -> Result<()>) -> FileResult {
+    let Task {
+        number, input, out, ..
+    } = task;
+    eprintln!(
+        "({}/{}) -> Processing: {} -> {}",
+        number,
+        total,
+        input.display(),
+        out.display()
+    );
+    let t0 = Instant::now();
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| run(task)));
+
+    let mut file_result = FileResult::new(input, Some(out), Outcome::Ok).for_page(task.page);
+    file_result.seconds = t0.elapsed().as_secs_f64();
+    match result {
+        Ok(Ok(())) => {
+            let dt = t0.elapsed();
+            eprintln!("OK {} -> {} ({:.2?})", input.display(), out.display(), dt);
+            file_result.output_bytes = fs::metadata(out).ok().map(|m| m.len());
+        }
+        Ok(Err(e)) if e.is::<worker::Crashed>() => {
+            eprintln!("ERR {} - {}", input.display(), e);
+            file_result.outcome = Outcome::Panicked;
+            file_result.message = Some(e.to_string());
+        }
+        Ok(Err(e)) => {
+            eprintln!("ERR {} - Error: {}", input.display(), e);
+            file_result.outcome = Outcome::Failed;
+            file_result.message = Some(format!("{:#}", e));
+        }
+        Err(panic) => {
+            let reason = panic_message(panic.as_ref());
+            eprintln!(
+                "ERR {} - Panic during conversion: {}",
+                input.display(),
+                reason
+            );
+            file_result.outcome = Outcome::Panicked;
+            file_result.message = Some(reason);
+        }
+    }
+    let _ = std::io::stderr().flush();
+    let _ = std::io::stdout().flush();
+    file_result
+}
+
+/// `--isolate` child: converts the one input named by the worker options and
+/// reports an error through `worker::ERROR_PREFIX` and the exit code.
+fn run_worker(args: &Args, operation: Operation, input: &Path, out: &Path) -> Result<ExitCode> {
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| match operation {
+        Operation::Encode => {
+            encoder::encode_tiff_to_jp2(input, args.worker_ifd, out, &args.encode_options()?)
+        }
+        Operation::Decode => decoder::decode_to_file(input, out, &args.decode_options()?),
+        Operation::Info | Operation::Validate => {
+            Err(anyhow!("--info and --validate have no workers"))
+        }
+    }));
+    let code = match result {
+        Ok(Ok(())) => return Ok(ExitCode::SUCCESS),
+        Ok(Err(e)) => {
+            eprintln!("{}{:#}", worker::ERROR_PREFIX, e);
+            report::EXIT_FAILED
+        }
+        Err(panic) => {
+            eprintln!("{}{}", worker::ERROR_PREFIX, panic_message(panic.as_ref()));
+            report::EXIT_PANICKED
+        }
+    };
+    Ok(ExitCode::from(code))
+}
+
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> String {
+    panic
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "<unknown reason>".to_string())
+}
+
+/// Picks the IFDs of `input` to encode, with the output name suffix for each
+/// (`_p0001`, ... with --all-pages). Without --page/--all-pages only the first
+/// page is encoded and a warning names the pages left out.
+/// A TIFF page picked for encoding.
+struct PageTarget {
+    ifd: usize,
+    /// 1-based page number, when the file has more than one page.
+    page: Option<usize>,
+    /// Output name suffix with --all-pages, e.g. `_p0002`.
+    suffix: Option<String>,
+}
+
+fn select_pages(args: &Args, input: &Path, log: &Log) -> Result<Vec<PageTarget>> {
+    let TiffPages { pages, skipped } = encoder::tiff_pages(input)?;
+    if skipped > 0 {
+        log.v1(format!(
+            "Skipping {} reduced-resolution/mask IFD(s) in {}",
+            skipped,
+            input.display()
+        ));
+    }
+    if pages.is_empty() {
+        return Err(anyhow!("No full-resolution page found"));
+    }
+
+    let target = |n: usize, suffix: Option<String>| PageTarget {
+        ifd: pages[n],
+        page: (pages.len() > 1).then_some(n + 1),
+        suffix,
+    };
+    if args.all_pages {
+        return Ok((0..pages.len())
+            .map(|n| target(n, Some(format!("_p{:04}", n + 1))))
+            .collect());
+    }
+    if let Some(page) = args.page {
+        if page as usize > pages.len() {
+            return Err(anyhow!(
+                "--page {} is out of range ({} has {} page(s))",
+                page,
+                input.display(),
+                pages.len()
+            ));
+        }
+        return Ok(vec![target(page as usize - 1, None)]);
+    }
+    if pages.len() > 1 {
+        eprintln!(
+            "WARN {} has {} pages; only page 1 is encoded (use --all-pages or --page N)",
+            input.display(),
+            pages.len()
+        );
+    }
+    Ok(vec![target(0, None)])
+}
+
+/// `dir/name.ext` -> `dir/name{suffix}.ext`
+fn with_stem_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut name = format!("{}{}", stem, suffix);
+    if let Some(ext) = path.extension() {
+        name.push('.');
+        name.push_str(&ext.to_string_lossy());
+    }
+    path.with_file_name(name)
 }
 
 fn collect_inputs(root: &Path, recursive: bool, operation: Operation) -> Result<Vec<PathBuf>> {
@@ -396,26 +1135,45 @@ fn collect_inputs(root: &Path, recursive: bool, operation: Operation) -> Result<
 fn accepts_input(path: &Path, operation: Operation) -> bool {
     match operation {
         Operation::Encode => info::is_tiff(path),
-        Operation::Decode | Operation::Info => info::is_jpeg2000(path),
+        Operation::Decode | Operation::Info | Operation::Validate => info::is_jpeg2000(path),
     }
 }
 
 fn derive_output_path(args: &Args, input: &Path, operation: Operation) -> Result<PathBuf> {
+    let normalized_path = output_path(args, input, operation);
+    eprintln!(
+        "Input: {} -> Output: {}",
+        input.display(),
+        normalized_path.display()
+    );
+    Ok(normalized_path)
+}
+
+/// Output path of `input`: next to it, the `-o` file (given the operation's
+/// extension if it has none), or under the `-o` directory at the same
+/// relative path as below the input root.
+fn output_path(args: &Args, input: &Path, operation: Operation) -> PathBuf {
     let extension = match operation {
         Operation::Encode => "jp2",
-        Operation::Decode => "tif",
-        Operation::Info => unreachable!("info mode has no output path"),
+        Operation::Decode => args.decode_format().extension(),
+        Operation::Info | Operation::Validate => {
+            unreachable!("info and validate modes have no output path")
+        }
     };
     let result = match &args.output {
         Some(out) => {
             if out.is_dir() || (!out.exists() && args.input.is_dir()) {
-                let file_name = input
-                    .file_stem()
-                    .and_then(|s| s.to_str())
-                    .unwrap_or("output");
-                let mut output_path = out.clone();
-                output_path.push(format!("{}.{}", file_name, extension));
-                output_path
+                let relative = input
+                    .strip_prefix(&args.input)
+                    .ok()
+                    .filter(|rel| rel.file_name().is_some())
+                    .map(Path::to_path_buf)
+                    .unwrap_or_else(|| {
+                        PathBuf::from(input.file_name().unwrap_or("output".as_ref()))
+                    });
+                out.join(relative).with_extension(extension)
+            } else if out.extension().is_none() {
+                out.with_extension(extension)
             } else {
                 out.clone()
             }
@@ -423,11 +1181,197 @@ fn derive_output_path(args: &Args, input: &Path, operation: Operation) -> Result
         None => input.with_extension(extension),
     };
 
-    let normalized_path = PathBuf::from(result.to_string_lossy().replace('\\', "/"));
-    eprintln!(
-        "Input: {} -> Output: {}",
-        input.display(),
-        normalized_path.display()
-    );
-    Ok(normalized_path)
+    PathBuf::from(result.to_string_lossy().replace('\\', "/"))
+}
+
+/// Fails when two conversions would write the same output (e.g. `scan.tif`
+/// and `scan.tiff`, page 2 of `a.tif` and `a_p0002.tif`, or several inputs
+/// with one `-o` file). Case-insensitive on Windows and macOS, whose file
+/// systems usually are.
+fn check_output_collisions(tasks: &[Task]) -> Result<()> {
+    let key = |path: &Path| {
+        let path = path.to_string_lossy();
+        if cfg!(any(windows, target_os = "macos")) {
+            path.to_lowercase()
+        } else {
+            path.into_owned()
+        }
+    };
+    let mut targets: HashMap<String, Vec<&Path>> = HashMap::new();
+    for task in tasks {
+        targets.entry(key(&task.out)).or_default().push(task.input);
+    }
+    let mut collisions: Vec<(String, Vec<&Path>)> = targets
+        .into_iter()
+        .filter(|(_, sources)| sources.len() > 1)
+        .collect();
+    if collisions.is_empty() {
+        return Ok(());
+    }
+    collisions.sort();
+    for (output, sources) in &collisions {
+        eprintln!("ERR output name collision: {}", output);
+        for source in sources {
+            eprintln!("      <- {}", source.display());
+        }
+    }
+    Err(anyhow!(
+        "{} output path(s) would be written by more than one input; rename the inputs or convert them separately",
+        collisions.len()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(argv: &[&str]) -> Args {
+        Args::try_parse_from(std::iter::once("tif2jp2").chain(argv.iter().copied())).unwrap()
+    }
+
+    #[test]
+    fn page_suffix_keeps_extension() {
+        let args = args(&["multi.tif", "--all-pages", "-o", "mp"]);
+        let out = output_path(&args, Path::new("multi.tif"), Operation::Encode);
+        assert_eq!(out, PathBuf::from("mp.jp2"));
+        assert_eq!(
+            with_stem_suffix(&out, "_p0002"),
+            PathBuf::from("mp_p0002.jp2")
+        );
+    }
+
+    #[test]
+    fn output_file_gets_decode_format_extension() {
+        let args = args(&["in.jp2", "--decode", "--output-format", "png", "-o", "out"]);
+        let out = output_path(&args, Path::new("in.jp2"), Operation::Decode);
+        assert_eq!(out, PathBuf::from("out.png"));
+    }
+
+    #[test]
+    fn page_output_collides_with_same_named_input() {
+        let task = |input: &'static str, ifd, out: &str| Task {
+            number: 1,
+            input: Path::new(input),
+            ifd,
+            page: None,
+            out: PathBuf::from(out),
+        };
+        let tasks = [
+            task("a.tif", 0, "a_p0001.jp2"),
+            task("a.tif", 1, "a_p0002.jp2"),
+            task("b.tif", 0, "b.jp2"),
+        ];
+        assert!(check_output_collisions(&tasks).is_ok());
+        let tasks = [
+            task("a.tif", 0, "a_p0001.jp2"),
+            task("a.tif", 1, "a_p0002.jp2"),
+            task("a_p0002.tif", 0, "a_p0002.jp2"),
+        ];
+        assert!(check_output_collisions(&tasks).is_err());
+    }
+
+    #[test]
+    fn output_file_extension_is_kept() {
+        let args = args(&["multi.tif", "-o", "scan.jpx"]);
+        let out = output_path(&args, Path::new("multi.tif"), Operation::Encode);
+        assert_eq!(out, PathBuf::from("scan.jpx"));
+    }
+
+    #[test]
+    fn journal_key_holds_only_output_shaping_options() {
+        let key = |argv: &[&str]| {
+            let args = args(argv);
+            args.journal_key(args.operation()).unwrap()
+        };
+        let base = key(&["dir", "--rate", "20"]);
+        assert_eq!(
+            base,
+            "tile=4096x4096 block=64x64 levels=6 order=RPCL rate=20 metadata=none \
+             dpi-box=true xmp-dpi=false tp-r=true precincts=true sop=true eph=true \
+             mct=true tlm=true plt=false bypass=true"
+        );
+        let same = [
+            &["--threads", "3"][..],
+            &["--max-memory", "1G"],
+            &["--avx2"],
+            &["--jobs", "2"],
+        ];
+        for extra in same {
+            assert_eq!(key(&[&["dir", "--rate", "20"][..], extra].concat()), base);
+        }
+        assert_ne!(key(&["dir", "--rate", "20", "--no-sop"]), base);
+        assert_ne!(key(&["dir", "--rate", "10"]), base);
+
+        let decode = key(&["dir", "--decode", "--reduce", "1", "--threads", "2"]);
+        assert_eq!(decode, "output-format=tiff reduce=1 native-precision=false");
+    }
+
+    /// A worker parsed from `worker_args` ends up with the parent's options
+    /// and none of its batch options.
+    fn worker_of(parent: &Args) -> Args {
+        let mut argv = vec![OsString::from("tif2jp2")];
+        argv.extend(parent.worker_args(parent.operation()));
+        argv.extend(["--worker-output", "out", "--", "-in.tif"].map(OsString::from));
+        Args::try_parse_from(argv).unwrap()
+    }
+
+    #[test]
+    fn worker_gets_encode_options_only() {
+        let parent = args(&[
+            "dir",
+            "-o",
+            "out",
+            "--jobs",
+            "3",
+            "--isolate",
+            "--journal",
+            "j.jsonl",
+            "--report",
+            "r.csv",
+            "--layers",
+            "40,10.5,0",
+            "--no-sop",
+            "--plt",
+            "--icc",
+            "p.icc",
+            "--max-memory",
+            "1G",
+            "--metadata",
+            "copy",
+        ]);
+        let worker = worker_of(&parent);
+        assert_eq!(
+            format!("{:?}", worker.encode_options().unwrap()),
+            format!("{:?}", parent.encode_options().unwrap())
+        );
+        assert_eq!(worker.input, PathBuf::from("-in.tif"));
+        assert_eq!(worker.output, None);
+        assert_eq!(worker.jobs, 1);
+        assert!(!worker.isolate && worker.journal.is_none() && worker.report.is_none());
+    }
+
+    #[test]
+    fn worker_gets_decode_options_only() {
+        let parent = args(&[
+            "dir",
+            "--decode",
+            "-o",
+            "out.png",
+            "--jobs",
+            "2",
+            "--isolate",
+            "--reduce",
+            "1",
+            "--region",
+            "1,2,30,40",
+            "--native-precision",
+        ]);
+        let worker = worker_of(&parent);
+        assert_eq!(worker.operation(), Operation::Decode);
+        assert_eq!(
+            format!("{:?}", worker.decode_options().unwrap()),
+            format!("{:?}", parent.decode_options().unwrap())
+        );
+        assert_eq!(worker.jobs, 1);
+    }
 }
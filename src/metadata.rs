@@ -0,0 +1,621 @@
+//! Embedded metadata (XMP, IPTC, EXIF): read from TIFF for `--metadata copy`
+//! and from JP2 'uuid'/'xml ' boxes for `--decode`.
+//!
+//! The IFDs are read straight from the file so that tag payloads keep their
+//! exact bytes, whatever type the writer declared (Photoshop stores IPTC as
+//! LONG, for example). Classic TIFF and BigTIFF are both handled.
+
+use std::fs::File;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use anyhow::{Context, Result, bail};
+use clap::ValueEnum;
+
+use crate::jp2box::{self, BoxDetails};
+
+const TAG_XMP: u16 = 700;
+const TAG_IPTC: u16 = 33723;
+const TAG_EXIF_IFD: u16 = 34665;
+/// Interoperability IFD pointer inside the EXIF IFD.
+const TAG_INTEROP_IFD: u16 = 40965;
+
+/// Largest tag payload copied (guards against corrupt counts).
+const MAX_PAYLOAD: u64 = 64 << 20;
+
+/// What `--metadata` does with embedded TIFF metadata.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum MetadataMode {
+    /// Drop XMP, IPTC and EXIF (archival default)
+    None,
+    /// Carry XMP, IPTC and EXIF into JP2 'uuid' boxes
+    Copy,
+}
+
+/// Metadata blocks found in one TIFF page or JP2 file.
+#[derive(Debug, Default)]
+pub struct EmbeddedMetadata {
+    /// XMP packet (tag 700).
+    pub xmp: Option<Vec<u8>>,
+    /// IPTC-IIM records (tag 33723).
+    pub iptc: Option<Vec<u8>>,
+    /// EXIF IFD rewritten as a standalone classic TIFF stream (header + one IFD).
+    pub exif: Option<Vec<u8>>,
+    /// Text of the first JP2 'xml ' box (ImageDescription on decode).
+    pub xml: Option<String>,
+}
+
+/// An IFD entry as listed in the IFD, before its value is read.
+struct Field {
+    tag: u16,
+    kind: u16,
+    count: u64,
+    /// The value-or-offset field (4 bytes, 8 in BigTIFF).
+    inline: Vec<u8>,
+}
+
+/// One raw IFD entry with its value bytes in file byte order.
+pub struct Entry {
+    pub tag: u16,
+    pub kind: u16,
+    count: u64,
+    pub value: Vec<u8>,
+}
+
+struct TiffReader<R> {
+    file: R,
+    file_len: u64,
+    little_endian: bool,
+    big_tiff: bool,
+}
+
+impl<R: Read + Seek> TiffReader<R> {
+    fn new(mut file: R, file_len: u64) -> Result<(Self, u64)> {
+        let mut header = [0u8; 16];
+        file.read_exact(&mut header[..8])
+            .context("TIFF header is truncated")?;
+        let little_endian = match &header[..2] {
+            b"II" => true,
+            b"MM" => false,
+            _ => bail!("Not a TIFF file"),
+        };
+        let mut reader = Self {
+            file,
+            file_len,
+            little_endian,
+            big_tiff: false,
+        };
+        let first_ifd = match reader.u16_at(&header[2..4]) {
+            42 => reader.u32_at(&header[4..8]) as u64,
+            43 => {
+                reader.big_tiff = true;
+                reader.file.read_exact(&mut header[8..16])?;
+                reader.u64_at(&header[8..16])
+            }
+            magic => bail!("Unknown TIFF version {}", magic),
+        };
+        Ok((reader, first_ifd))
+    }
+
+    fn u16_at(&self, b: &[u8]) -> u16 {
+        let b = [b[0], b[1]];
+        if self.little_endian {
+            u16::from_le_bytes(b)
+        } else {
+            u16::from_be_bytes(b)
+        }
+    }
+
+    fn u32_at(&self, b: &[u8]) -> u32 {
+        let b = [b[0], b[1], b[2], b[3]];
+        if self.little_endian {
+            u32::from_le_bytes(b)
+        } else {
+            u32::from_be_bytes(b)
+        }
+    }
+
+    fn u64_at(&self, b: &[u8]) -> u64 {
+        let b: [u8; 8] = b[..8].try_into().unwrap_or_default();
+        if self.little_endian {
+            u64::from_le_bytes(b)
+        } else {
+            u64::from_be_bytes(b)
+        }
+    }
+
+    fn read_at(&mut self, offset: u64, len: u64) -> Result<Vec<u8>> {
+        if len > MAX_PAYLOAD || offset.saturating_add(len) > self.file_len {
+            bail!(
+                "TIFF value at offset {} ({} bytes) is out of bounds",
+                offset,
+                len
+            );
+        }
+        self.file.seek(SeekFrom::Start(offset))?;
+        let mut buf = vec![0u8; len as usize];
+        self.file.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Entries of the IFD at `offset` and the offset of the next IFD. Values
+    /// are read separately (`read_value`), so that a bad offset in a tag
+    /// nobody asked for does no harm.
+    fn read_ifd(&mut self, offset: u64) -> Result<(Vec<Field>, u64)> {
+        let (count_len, entry_len, inline_len) = if self.big_tiff {
+            (8, 20, 8)
+        } else {
+            (2, 12, 4)
+        };
+        let count_bytes = self.read_at(offset, count_len)?;
+        let count = if self.big_tiff {
+            self.u64_at(&count_bytes)
+        } else {
+            self.u16_at(&count_bytes) as u64
+        };
+        let table = self.read_at(
+            offset + count_len,
+            count.saturating_mul(entry_len).saturating_add(inline_len),
+        )?;
+
+        let fields = table
+            .chunks_exact(entry_len as usize)
+            .take(count as usize)
+            .map(|raw| {
+                let (count, inline) = if self.big_tiff {
+                    (self.u64_at(&raw[4..12]), &raw[12..20])
+                } else {
+                    (self.u32_at(&raw[4..8]) as u64, &raw[8..12])
+                };
+                Field {
+                    tag: self.u16_at(&raw[0..2]),
+                    kind: self.u16_at(&raw[2..4]),
+                    count,
+                    inline: inline.to_vec(),
+                }
+            })
+            .collect();
+        let next_field = &table[(count * entry_len) as usize..];
+        let next = if self.big_tiff {
+            self.u64_at(next_field)
+        } else {
+            self.u32_at(next_field) as u64
+        };
+        Ok((fields, next))
+    }
+
+    /// Reads the value bytes of `field`, inline or at its offset.
+    fn read_value(&mut self, field: &Field) -> Result<Entry> {
+        let Some(size) = type_size(field.kind) else {
+            bail!("tag {} has unknown type {}", field.tag, field.kind);
+        };
+        let len = size.saturating_mul(field.count);
+        let value = if len <= field.inline.len() as u64 {
+            field.inline[..len as usize].to_vec()
+        } else {
+            let at = if self.big_tiff {
+                self.u64_at(&field.inline)
+            } else {
+                self.u32_at(&field.inline) as u64
+            };
+            self.read_at(at, len)
+                .with_context(|| format!("tag {}", field.tag))?
+        };
+        Ok(Entry {
+            tag: field.tag,
+            kind: field.kind,
+            count: field.count,
+            value,
+        })
+    }
+
+    /// Offset stored in a LONG/IFD (or LONG8/IFD8) entry.
+    fn pointer(&self, field: &Field) -> Option<u64> {
+        match (field.kind, field.inline.len()) {
+            (4 | 13, 4..) => Some(self.u32_at(&field.inline) as u64),
+            (16 | 18, 8..) => Some(self.u64_at(&field.inline)),
+            _ => None,
+        }
+    }
+
+    /// Reads the relocatable entries of the EXIF IFD at `offset`; entries whose
+    /// value cannot be read are left out and counted.
+    fn read_exif_entries(&mut self, offset: u64) -> Result<(Vec<Entry>, usize)> {
+        let (fields, _) = self.read_ifd(offset)?;
+        let mut entries = Vec::new();
+        let mut unreadable = 0;
+        // Unknown types are skipped: their value length cannot be told
+        for field in fields
+            .iter()
+            .filter(|f| relocatable(f) && type_size(f.kind).is_some())
+        {
+            match self.read_value(field) {
+                Ok(entry) => entries.push(entry),
+                Err(_) => unreadable += 1,
+            }
+        }
+        Ok((entries, unreadable))
+    }
+}
+
+/// Byte size of one value of a TIFF field type.
+fn type_size(kind: u16) -> Option<u64> {
+    match kind {
+        1 | 2 | 6 | 7 => Some(1),
+        3 | 8 => Some(2),
+        4 | 9 | 11 | 13 => Some(4),
+        5 | 10 | 12 | 16 | 17 | 18 => Some(8),
+        _ => None,
+    }
+}
+
+/// Reads XMP, IPTC and EXIF from page `ifd` (main IFD chain index) of the
+/// TIFF in `file`; `path` only names it in warnings.
+pub fn read_tiff_metadata<R: Read + Seek>(
+    file: R,
+    file_len: u64,
+    ifd: usize,
+    path: &Path,
+) -> Result<EmbeddedMetadata> {
+    let (mut reader, mut offset) = TiffReader::new(file, file_len)?;
+    for _ in 0..ifd {
+        if offset == 0 {
+            bail!("TIFF has no IFD {}", ifd);
+        }
+        offset = reader.read_ifd(offset)?.1;
+    }
+    if offset == 0 {
+        bail!("TIFF has no IFD {}", ifd);
+    }
+    let (fields, _) = reader.read_ifd(offset)?;
+    let find = |tag: u16| fields.iter().find(|f| f.tag == tag);
+    let warn = |what: &str, e: anyhow::Error| {
+        eprintln!("WARN {}: {} not copied: {:#}", path.display(), what, e);
+    };
+
+    // Each block on its own: a broken one is skipped, the others are kept
+    let mut payload = |tag: u16, what: &str| match reader.read_value(find(tag)?) {
+        Ok(entry) => Some(entry.value).filter(|v| !v.is_empty()),
+        Err(e) => {
+            warn(what, e);
+            None
+        }
+    };
+    let xmp = payload(TAG_XMP, "XMP");
+    let iptc = payload(TAG_IPTC, "IPTC");
+    let exif = match find(TAG_EXIF_IFD).and_then(|f| reader.pointer(f)) {
+        Some(exif_offset) => match reader.read_exif_entries(exif_offset) {
+            Ok((entries, unreadable)) => {
+                if unreadable > 0 {
+                    eprintln!(
+                        "WARN {}: {} EXIF entries with unreadable values not copied",
+                        path.display(),
+                        unreadable
+                    );
+                }
+                Some(build_exif_stream(&entries, reader.little_endian))
+            }
+            Err(e) => {
+                warn("EXIF", e);
+                None
+            }
+        },
+        None => None,
+    };
+    Ok(EmbeddedMetadata {
+        xmp,
+        iptc,
+        exif,
+        xml: None,
+    })
+}
+
+/// Entries that keep their meaning outside the original file: sub-IFD
+/// pointers and 64-bit types cannot be relocated and are left out.
+fn relocatable(field: &Field) -> bool {
+    field.tag != TAG_INTEROP_IFD
+        && field.tag != TAG_EXIF_IFD
+        && !matches!(field.kind, 13 | 16 | 17 | 18)
+        && field.count <= u32::MAX as u64
+}
+
+/// Writes the EXIF entries as a classic TIFF stream (same byte order), laid
+/// out as the Exif specification expects: IFD0 at offset 8 holding only the
+/// EXIF IFD pointer, then the EXIF IFD and its values.
+fn build_exif_stream(entries: &[Entry], little_endian: bool) -> Vec<u8> {
+    let u16b = |v: u16| {
+        if little_endian {
+            v.to_le_bytes()
+        } else {
+            v.to_be_bytes()
+        }
+    };
+    let u32b = |v: u32| {
+        if little_endian {
+            v.to_le_bytes()
+        } else {
+            v.to_be_bytes()
+        }
+    };
+
+    // Header and IFD0 (one entry) take 26 bytes
+    let exif_ifd = 8 + 2 + 12 + 4;
+    let mut out = Vec::new();
+    out.extend_from_slice(if little_endian { b"II" } else { b"MM" });
+    out.extend_from_slice(&u16b(42));
+    out.extend_from_slice(&u32b(8));
+    out.extend_from_slice(&u16b(1));
+    out.extend_from_slice(&u16b(TAG_EXIF_IFD));
+    out.extend_from_slice(&u16b(4));
+    out.extend_from_slice(&u32b(1));
+    out.extend_from_slice(&u32b(exif_ifd as u32));
+    out.extend_from_slice(&u32b(0));
+    out.extend_from_slice(&u16b(entries.len() as u16));
+
+    // Values longer than 4 bytes follow the IFD, word aligned
+    let mut data_offset = exif_ifd + 2 + 12 * entries.len() + 4;
+    let mut data = Vec::new();
+    for entry in entries {
+        out.extend_from_slice(&u16b(entry.tag));
+        out.extend_from_slice(&u16b(entry.kind));
+        out.extend_from_slice(&u32b(entry.count as u32));
+        if entry.value.len() <= 4 {
+            let mut field = [0u8; 4];
+            field[..entry.value.len()].copy_from_slice(&entry.value);
+            out.extend_from_slice(&field);
+        } else {
+            out.extend_from_slice(&u32b(data_offset as u32));
+            data.extend_from_slice(&entry.value);
+            if entry.value.len() % 2 == 1 {
+                data.push(0);
+            }
+            data_offset += entry.value.len().next_multiple_of(2);
+        }
+    }
+    out.extend_from_slice(&u32b(0));
+    out.extend_from_slice(&data);
+    out
+}
+
+// --- JP2 boxes -> TIFF tags ----------------------------------------------------
+
+/// Reads the top-level XMP, EXIF and IPTC 'uuid' boxes and the first 'xml '
+/// box of a JP2 file (the first box of each kind wins).
+pub fn read_jp2_metadata(path: &Path) -> Result<EmbeddedMetadata> {
+    let mut file = File::open(path).with_context(|| format!("Open {}", path.display()))?;
+    let file_len = file.metadata()?.len();
+    let boxes = jp2box::read_box_tree(&mut file, file_len)?;
+    let mut found = EmbeddedMetadata::default();
+    for jp2_box in &boxes {
+        match &jp2_box.details {
+            Some(BoxDetails::Uuid { uuid }) => {
+                let slot = match *uuid {
+                    jp2box::XMP_UUID => &mut found.xmp,
+                    jp2box::EXIF_UUID => &mut found.exif,
+                    jp2box::IPTC_UUID => &mut found.iptc,
+                    _ => continue,
+                };
+                if slot.is_none() {
+                    let payload = jp2box::read_payload(&mut file, jp2_box)?;
+                    *slot = payload.get(16..).map(<[u8]>::to_vec);
+                }
+            }
+            Some(BoxDetails::Xml { .. }) if found.xml.is_none() => {
+                let payload = jp2box::read_payload(&mut file, jp2_box)?;
+                found.xml = Some(String::from_utf8_lossy(&payload).into_owned());
+            }
+            _ => {}
+        }
+    }
+    // The JPEG APP1 style "Exif\0\0" prefix is optional
+    if let Some(exif) = &mut found.exif
+        && exif.starts_with(b"Exif\0\0")
+    {
+        exif.drain(..6);
+    }
+    Ok(found)
+}
+
+/// EXIF entries from a TIFF-structured EXIF block, with values converted to
+/// the native byte order (as the tiff encoder writes them). The EXIF IFD is
+/// taken from the IFD0 pointer; a block without one is read as a bare IFD.
+pub fn parse_exif_stream(stream: &[u8]) -> Result<Vec<Entry>> {
+    let (mut reader, ifd0) = TiffReader::new(Cursor::new(stream), stream.len() as u64)
+        .context("EXIF block is not TIFF structured")?;
+    let (fields, _) = reader.read_ifd(ifd0)?;
+    let exif_ifd = fields
+        .iter()
+        .find(|f| f.tag == TAG_EXIF_IFD)
+        .and_then(|f| reader.pointer(f))
+        .unwrap_or(ifd0);
+    let (mut entries, unreadable) = reader.read_exif_entries(exif_ifd)?;
+    if unreadable > 0 {
+        bail!("{} EXIF entries point outside the block", unreadable);
+    }
+    if reader.little_endian != cfg!(target_endian = "little") {
+        for entry in &mut entries {
+            // Rationals swap as two 32-bit halves
+            let width = match entry.kind {
+                5 | 10 => 4,
+                kind => type_size(kind).unwrap_or(1) as usize,
+            };
+            if width > 1 {
+                entry
+                    .value
+                    .chunks_exact_mut(width)
+                    .for_each(<[u8]>::reverse);
+            }
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const XMP: &[u8] = b"<x:xmpmeta/>";
+    const DATE: &[u8] = b"2024:01:02 03:04:05\0";
+    /// ExposureTime 1/125.
+    const RATIONAL: [u8; 8] = [1, 0, 0, 0, 125, 0, 0, 0];
+
+    /// Offsets of the values in `tiff()`, far from where the rewritten EXIF
+    /// stream puts them.
+    const XMP_AT: u32 = 38;
+    const EXIF_IFD_AT: u32 = 64;
+    const RATIONAL_AT: u32 = 120;
+    const DATE_AT: u32 = 200;
+
+    /// Little-endian IFD entry.
+    fn entry(tag: u16, kind: u16, count: u32, field: u32) -> Vec<u8> {
+        [
+            &tag.to_le_bytes()[..],
+            &kind.to_le_bytes(),
+            &count.to_le_bytes(),
+            &field.to_le_bytes(),
+        ]
+        .concat()
+    }
+
+    fn put(file: &mut Vec<u8>, at: u32, bytes: &[u8]) {
+        let at = at as usize;
+        if file.len() < at + bytes.len() {
+            file.resize(at + bytes.len(), 0);
+        }
+        file[at..at + bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// Classic little-endian TIFF whose IFD0 holds an XMP packet and an EXIF
+    /// IFD with a RATIONAL, an ASCII and an Interoperability pointer entry.
+    fn tiff(xmp_at: u32, date_at: u32) -> Vec<u8> {
+        let mut file = b"II*\0\x08\0\0\0".to_vec();
+        let ifd0 = [
+            &2u16.to_le_bytes()[..],
+            &entry(TAG_XMP, 1, XMP.len() as u32, xmp_at),
+            &entry(TAG_EXIF_IFD, 4, 1, EXIF_IFD_AT),
+            &0u32.to_le_bytes(),
+        ]
+        .concat();
+        put(&mut file, 8, &ifd0);
+        put(&mut file, XMP_AT, XMP);
+        let exif_ifd = [
+            &3u16.to_le_bytes()[..],
+            &entry(0x829A, 5, 1, RATIONAL_AT),
+            &entry(0x9003, 2, DATE.len() as u32, date_at),
+            &entry(TAG_INTEROP_IFD, 4, 1, 999),
+            &0u32.to_le_bytes(),
+        ]
+        .concat();
+        put(&mut file, EXIF_IFD_AT, &exif_ifd);
+        put(&mut file, RATIONAL_AT, &RATIONAL);
+        put(&mut file, DATE_AT, DATE);
+        file
+    }
+
+    /// Reads the metadata of IFD 0.
+    fn read(bytes: &[u8]) -> Result<EmbeddedMetadata> {
+        read_tiff_metadata(
+            Cursor::new(bytes),
+            bytes.len() as u64,
+            0,
+            Path::new("test.tif"),
+        )
+    }
+
+    fn summary(entries: &[Entry]) -> Vec<(u16, u16, u64, Vec<u8>)> {
+        entries
+            .iter()
+            .map(|e| (e.tag, e.kind, e.count, e.value.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn exif_values_are_relocated() {
+        let found = read(&tiff(XMP_AT, DATE_AT)).unwrap();
+        assert_eq!(found.xmp.as_deref(), Some(XMP));
+        assert_eq!(found.iptc, None);
+
+        // IFD0 with only the EXIF pointer, the EXIF IFD right after it and
+        // the values behind that, at new offsets
+        let stream = found.exif.unwrap();
+        assert_eq!(&stream[..8], b"II*\0\x08\0\0\0");
+        assert_eq!(&stream[10..12], &TAG_EXIF_IFD.to_le_bytes());
+        let exif_ifd = u32::from_le_bytes(stream[18..22].try_into().unwrap());
+        assert_eq!(exif_ifd, 26);
+        assert_eq!(u16::from_le_bytes([stream[26], stream[27]]), 2);
+        let rational_at = u32::from_le_bytes(stream[36..40].try_into().unwrap()) as usize;
+        assert_eq!(rational_at, 26 + 2 + 2 * 12 + 4);
+        assert_eq!(&stream[rational_at..rational_at + 8], &RATIONAL);
+        let date_at = u32::from_le_bytes(stream[48..52].try_into().unwrap()) as usize;
+        assert_eq!(&stream[date_at..date_at + DATE.len()], DATE);
+
+        let entries = parse_exif_stream(&stream).unwrap();
+        assert_eq!(
+            summary(&entries),
+            [
+                (0x829A, 5, 1, RATIONAL.to_vec()),
+                (0x9003, 2, DATE.len() as u64, DATE.to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn big_endian_exif_is_read_in_native_order() {
+        let entries = [
+            Entry {
+                tag: 0x829A,
+                kind: 5,
+                count: 1,
+                value: vec![0, 0, 0, 1, 0, 0, 0, 125],
+            },
+            Entry {
+                tag: 0xA002,
+                kind: 3,
+                count: 1,
+                value: vec![0x01, 0x02],
+            },
+        ];
+        let stream = build_exif_stream(&entries, false);
+        assert_eq!(&stream[..4], b"MM\0*");
+        let parsed = parse_exif_stream(&stream).unwrap();
+        let native = |be: &[u8]| {
+            let mut v = be.to_vec();
+            if cfg!(target_endian = "little") {
+                v.reverse();
+            }
+            v
+        };
+        assert_eq!(
+            parsed[0].value,
+            [native(&[0, 0, 0, 1]), native(&[0, 0, 0, 125])].concat()
+        );
+        assert_eq!(parsed[1].value, native(&[0x01, 0x02]));
+    }
+
+    #[test]
+    fn bad_offsets_skip_only_the_affected_metadata() {
+        let found = read(&tiff(1 << 30, DATE_AT)).unwrap();
+        assert_eq!(found.xmp, None);
+        assert_eq!(parse_exif_stream(&found.exif.unwrap()).unwrap().len(), 2);
+
+        let found = read(&tiff(XMP_AT, 1 << 30)).unwrap();
+        assert_eq!(found.xmp.as_deref(), Some(XMP));
+        let entries = parse_exif_stream(&found.exif.unwrap()).unwrap();
+        assert_eq!(summary(&entries), [(0x829A, 5, 1, RATIONAL.to_vec())]);
+    }
+
+    #[test]
+    fn truncated_input_is_an_error_not_a_panic() {
+        let file = tiff(XMP_AT, DATE_AT);
+        for cut in 0..file.len() {
+            // Cut values are skipped; a cut IFD0 fails the read
+            let _ = read(&file[..cut]);
+        }
+        assert!(read(&file[..20]).is_err());
+
+        let stream = read(&file).unwrap().exif.unwrap();
+        for cut in 0..stream.len() {
+            assert!(parse_exif_stream(&stream[..cut]).is_err(), "cut at {}", cut);
+        }
+    }
+}
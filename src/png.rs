@@ -0,0 +1,73 @@
+//! PNG output for decoded JP2 images through the `png` crate: 8/16-bit gray,
+//! RGB and their alpha variants, with the ICC profile as `iCCP` and the DPI as `pHYs`.
+
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use ::png::{BitDepth, ColorType, Encoder, Info, PixelDimensions, Unit};
+use anyhow::{Context, Result, bail};
+
+use crate::decoder::{DecodedColor, DecodedImage, DecodedPixels};
+
+pub fn write_png(path: &Path, image: &DecodedImage) -> Result<()> {
+    let color_type = match image.color {
+        DecodedColor::Gray => ColorType::Grayscale,
+        DecodedColor::Rgb => ColorType::Rgb,
+        DecodedColor::GrayAlpha => ColorType::GrayscaleAlpha,
+        DecodedColor::Rgba => ColorType::Rgba,
+        DecodedColor::Cmyk => bail!("PNG cannot hold CMYK; decode to TIFF instead"),
+    };
+    if image.native_precision.is_some() {
+        bail!(
+            "PNG samples must span the full 8/16-bit range; use TIFF or PNM with --native-precision"
+        );
+    }
+    if image.alpha_type == Some(2) {
+        eprintln!(
+            "WARN {}: premultiplied alpha is written as-is, PNG expects straight alpha",
+            path.display()
+        );
+    }
+
+    // Rows as big-endian samples
+    let (bit_depth, raw): (_, Cow<[u8]>) = match &image.pixels {
+        DecodedPixels::U8(pixels) => (BitDepth::Eight, Cow::Borrowed(pixels)),
+        DecodedPixels::U16(pixels) => (
+            BitDepth::Sixteen,
+            Cow::Owned(pixels.iter().flat_map(|v| v.to_be_bytes()).collect()),
+        ),
+    };
+
+    let mut info = Info::with_size(image.width, image.height);
+    info.color_type = color_type;
+    info.bit_depth = bit_depth;
+    info.icc_profile = image
+        .icc_profile
+        .as_deref()
+        .filter(|icc| !icc.is_empty())
+        .map(Cow::Borrowed);
+    if let Some((horizontal, vertical)) = image.dpi {
+        // pHYs holds pixels per metre
+        let ppm = |dpi: f64| (dpi / 0.0254).round().clamp(1.0, u32::MAX as f64) as u32;
+        info.pixel_dims = Some(PixelDimensions {
+            xppu: ppm(horizontal),
+            yppu: ppm(vertical),
+            unit: Unit::Meter,
+        });
+    }
+
+    let file = File::create(path).with_context(|| format!("creating {}", path.display()))?;
+    let encoder = Encoder::with_info(BufWriter::new(file), info)?;
+    let mut writer = encoder
+        .write_header()
+        .with_context(|| format!("writing {}", path.display()))?;
+    writer
+        .write_image_data(&raw)
+        .with_context(|| format!("writing {}", path.display()))?;
+    writer
+        .finish()
+        .with_context(|| format!("writing {}", path.display()))?;
+    Ok(())
+}
@@ -0,0 +1,113 @@
+//! Binary PGM (P5) and PPM (P6) writer for decoded JP2 images.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result, bail};
+
+use crate::decoder::{DecodedColor, DecodedImage, DecodedPixels};
+
+/// The PNM variant an output name asks for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flavour {
+    /// `.pgm`: gray only.
+    Pgm,
+    /// `.ppm`: RGB only.
+    Ppm,
+    /// `.pnm` or any other name: PGM or PPM, whichever fits the image.
+    Any,
+}
+
+impl Flavour {
+    pub fn from_path(path: &Path) -> Self {
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(str::to_ascii_lowercase);
+        match extension.as_deref() {
+            Some("pgm") => Flavour::Pgm,
+            Some("ppm") => Flavour::Ppm,
+            _ => Flavour::Any,
+        }
+    }
+}
+
+/// Magic number for `color`, refusing an image the requested flavour cannot hold.
+fn magic(color: DecodedColor, flavour: Flavour) -> Result<&'static str> {
+    let magic = match color {
+        DecodedColor::Gray => "P5",
+        DecodedColor::Rgb => "P6",
+        DecodedColor::GrayAlpha | DecodedColor::Rgba => {
+            bail!("PGM/PPM have no alpha channel; decode to PNG or TIFF instead")
+        }
+        DecodedColor::Cmyk => bail!("PGM/PPM cannot hold CMYK; decode to TIFF instead"),
+    };
+    match (flavour, magic) {
+        (Flavour::Pgm, "P6") => bail!("the image is RGB, a .pgm holds gray only; use .ppm or .pnm"),
+        (Flavour::Ppm, "P5") => bail!("the image is gray, a .ppm holds RGB only; use .pgm or .pnm"),
+        _ => Ok(magic),
+    }
+}
+
+/// Writes gray images as PGM and RGB as PPM, as far as `flavour` allows. With
+/// native precision the maxval is the true sample range (e.g. 4095 for
+/// 12-bit), otherwise 255/65535.
+pub fn write_pnm(path: &Path, image: &DecodedImage, flavour: Flavour) -> Result<()> {
+    let magic = magic(image.color, flavour)?;
+    let maxval: u32 = match (&image.pixels, &image.native_precision) {
+        (_, Some(precision)) => {
+            let bits = precision.iter().copied().max().unwrap_or(8).clamp(1, 16);
+            (1 << bits) - 1
+        }
+        (DecodedPixels::U8(_), None) => 255,
+        (DecodedPixels::U16(_), None) => 65535,
+    };
+
+    let file = File::create(path).with_context(|| format!("creating {}", path.display()))?;
+    let mut out = BufWriter::new(file);
+    write!(
+        out,
+        "{}\n{} {}\n{}\n",
+        magic, image.width, image.height, maxval
+    )?;
+    // One byte per sample up to maxval 255, otherwise two bytes, big-endian
+    match &image.pixels {
+        DecodedPixels::U8(pixels) => out.write_all(pixels)?,
+        DecodedPixels::U16(pixels) if maxval < 256 => {
+            let bytes: Vec<u8> = pixels.iter().map(|&v| v as u8).collect();
+            out.write_all(&bytes)?;
+        }
+        DecodedPixels::U16(pixels) => {
+            let bytes: Vec<u8> = pixels.iter().flat_map(|v| v.to_be_bytes()).collect();
+            out.write_all(&bytes)?;
+        }
+    }
+    out.flush()
+        .with_context(|| format!("writing {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flavour_follows_the_extension() {
+        assert_eq!(Flavour::from_path(Path::new("a.PGM")), Flavour::Pgm);
+        assert_eq!(Flavour::from_path(Path::new("a.ppm")), Flavour::Ppm);
+        assert_eq!(Flavour::from_path(Path::new("a.pnm")), Flavour::Any);
+        assert_eq!(Flavour::from_path(Path::new("a")), Flavour::Any);
+    }
+
+    #[test]
+    fn colour_must_fit_the_flavour() {
+        assert_eq!(magic(DecodedColor::Gray, Flavour::Pgm).unwrap(), "P5");
+        assert_eq!(magic(DecodedColor::Rgb, Flavour::Ppm).unwrap(), "P6");
+        assert_eq!(magic(DecodedColor::Gray, Flavour::Any).unwrap(), "P5");
+        assert_eq!(magic(DecodedColor::Rgb, Flavour::Any).unwrap(), "P6");
+        assert!(magic(DecodedColor::Rgb, Flavour::Pgm).is_err());
+        assert!(magic(DecodedColor::Gray, Flavour::Ppm).is_err());
+        assert!(magic(DecodedColor::Rgba, Flavour::Any).is_err());
+    }
+}
@@ -0,0 +1,665 @@
+//! Archival JP2 profiles: the NDK preset applied by `--archival-master-ndk` and
+//! the `--validate` conformance checks share the tables below.
+
+use std::path::Path;
+
+use clap::ValueEnum;
+
+use crate::codestream::{self, CodestreamInfo};
+use crate::info::Jp2Info;
+use crate::jp2box::{BoxDetails, Jp2Box};
+
+use Severity::{Recommended, Required};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Profile {
+    /// Czech National Digital Library archival master (same rules as --archival-master-ndk)
+    Ndk,
+    /// FADGI / Library of Congress lossless archival master
+    Fadgi,
+    /// Metamorfoze / KB (National Library of the Netherlands) master
+    Metamorfoze,
+}
+
+impl Profile {
+    pub fn spec(self) -> &'static ProfileSpec {
+        match self {
+            Profile::Ndk => &NDK,
+            Profile::Fadgi => &FADGI,
+            Profile::Metamorfoze => &METAMORFOZE,
+        }
+    }
+}
+
+/// Precinct layout per resolution level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Precincts {
+    /// 256x256 for the two lowest resolutions, 128x128 above (what the NDK preset writes)
+    LowestTwo256,
+    /// 256x256 for the two highest resolutions, 128x128 below (Kakadu-style `Cprecincts`)
+    HighestTwo256,
+}
+
+impl Precincts {
+    /// Nominal precinct size per resolution, lowest resolution first (before the code-block minimum).
+    pub fn sizes(self, resolutions: u32) -> Vec<u32> {
+        (0..resolutions)
+            .map(|r| {
+                let large = match self {
+                    Precincts::LowestTwo256 => r < 2,
+                    Precincts::HighestTwo256 => r + 2 >= resolutions,
+                };
+                if large { 256 } else { 128 }
+            })
+            .collect()
+    }
+}
+
+/// How a broken rule is reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// Mismatch is a FAIL
+    Required,
+    /// Mismatch is a WARN
+    Recommended,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rule {
+    Lossless,
+    Layers,
+    Order,
+    Levels,
+    CodeBlock,
+    Tiles,
+    Precincts,
+    Sop,
+    Eph,
+    TilePartsR,
+    Tlm,
+    Bypass,
+    Mct,
+    Icc,
+}
+
+impl Rule {
+    fn label(self) -> &'static str {
+        match self {
+            Rule::Lossless => "lossless 5/3",
+            Rule::Layers => "quality layers",
+            Rule::Order => "progression order",
+            Rule::Levels => "decomposition levels",
+            Rule::CodeBlock => "code-block size",
+            Rule::Tiles => "tile size",
+            Rule::Precincts => "precincts",
+            Rule::Sop => "SOP markers",
+            Rule::Eph => "EPH markers",
+            Rule::TilePartsR => "tile-parts by resolution (R)",
+            Rule::Tlm => "TLM markers",
+            Rule::Bypass => "coding bypass",
+            Rule::Mct => "reversible MCT (RGB)",
+            Rule::Icc => "ICC profile",
+        }
+    }
+}
+
+pub struct ProfileSpec {
+    pub name: &'static str,
+    /// Resolutions the encoder preset writes (`--levels`).
+    pub resolutions: u32,
+    /// Accepted decomposition levels (resolutions - 1).
+    pub min_levels: u8,
+    pub max_levels: u8,
+    pub order: &'static str,
+    pub layers: u16,
+    pub tile: (u32, u32),
+    pub block: (u32, u32),
+    /// Precinct layout the encoder preset writes.
+    pub precincts: Precincts,
+    /// Further layouts `--validate` accepts.
+    pub other_precincts: &'static [Precincts],
+    /// Rules checked by `--validate`, in report order.
+    pub rules: &'static [(Rule, Severity)],
+}
+
+pub const NDK: ProfileSpec = ProfileSpec {
+    name: "NDK",
+    resolutions: 6,
+    min_levels: 5,
+    max_levels: 6,
+    order: "RPCL",
+    layers: 1,
+    tile: (4096, 4096),
+    block: (64, 64),
+    precincts: Precincts::LowestTwo256,
+    // tif2jp2 has always written the 256x256 precincts at the lowest two
+    // resolutions, while NDK masters made with Kakadu's usual
+    // Cprecincts={256,256},{256,256},{128,128} have them at the highest two.
+    // Both are in the archives, so both pass.
+    other_precincts: &[Precincts::HighestTwo256],
+    rules: &[
+        (Rule::Lossless, Required),
+        (Rule::Layers, Required),
+        (Rule::Order, Required),
+        (Rule::Levels, Required),
+        (Rule::CodeBlock, Required),
+        (Rule::Tiles, Recommended),
+        (Rule::Precincts, Required),
+        (Rule::Sop, Required),
+        (Rule::Eph, Required),
+        (Rule::TilePartsR, Required),
+        (Rule::Tlm, Required),
+        (Rule::Bypass, Required),
+        (Rule::Mct, Required),
+        (Rule::Icc, Required),
+    ],
+};
+
+pub const FADGI: ProfileSpec = ProfileSpec {
+    name: "FADGI",
+    resolutions: 6,
+    min_levels: 5,
+    max_levels: 6,
+    order: "RPCL",
+    layers: 1,
+    tile: (1024, 1024),
+    block: (64, 64),
+    precincts: Precincts::HighestTwo256,
+    other_precincts: &[],
+    rules: &[
+        (Rule::Lossless, Required),
+        (Rule::Layers, Recommended),
+        (Rule::Order, Recommended),
+        (Rule::Levels, Recommended),
+        (Rule::CodeBlock, Recommended),
+        (Rule::Tiles, Recommended),
+        (Rule::Precincts, Recommended),
+        (Rule::Sop, Recommended),
+        (Rule::Eph, Recommended),
+        (Rule::Tlm, Recommended),
+        (Rule::Icc, Required),
+    ],
+};
+
+pub const METAMORFOZE: ProfileSpec = ProfileSpec {
+    name: "Metamorfoze",
+    resolutions: 6,
+    min_levels: 5,
+    max_levels: 5,
+    order: "RPCL",
+    layers: 1,
+    tile: (1024, 1024),
+    block: (64, 64),
+    precincts: Precincts::HighestTwo256,
+    other_precincts: &[],
+    rules: &[
+        (Rule::Lossless, Required),
+        (Rule::Layers, Required),
+        (Rule::Order, Required),
+        (Rule::Levels, Required),
+        (Rule::CodeBlock, Required),
+        (Rule::Tiles, Recommended),
+        (Rule::Precincts, Required),
+        (Rule::Sop, Required),
+        (Rule::Eph, Required),
+        (Rule::Bypass, Required),
+        (Rule::Icc, Required),
+    ],
+};
+
+// --- Validation ---
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl Status {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Status::Pass => "PASS",
+            Status::Warn => "WARN",
+            Status::Fail => "FAIL",
+        }
+    }
+}
+
+pub struct Check {
+    pub requirement: &'static str,
+    pub status: Status,
+    /// What the file has (and what was expected on mismatch).
+    pub detail: String,
+}
+
+/// Checks `info` against every rule of `spec`.
+pub fn validate(info: &Jp2Info, spec: &ProfileSpec) -> Vec<Check> {
+    let Some(cs) = &info.codestream else {
+        return vec![Check {
+            requirement: "codestream",
+            status: Status::Fail,
+            detail: "codestream markers could not be read".to_string(),
+        }];
+    };
+    spec.rules
+        .iter()
+        .map(|&(rule, severity)| {
+            let (mut ok, mut detail) = check_rule(rule, info, cs, spec);
+            // The rule read the main header only; overridden values went unchecked
+            if let Some(markers) = overrides(rule, cs) {
+                ok = false;
+                detail = format!("{}; overridden by {}", detail, markers);
+            }
+            let status = match (ok, severity) {
+                (true, _) => Status::Pass,
+                (false, Required) => Status::Fail,
+                (false, Recommended) => Status::Warn,
+            };
+            Check {
+                requirement: rule.label(),
+                status,
+                detail,
+            }
+        })
+        .collect()
+}
+
+/// Worst status of a report (PASS when empty).
+pub fn overall(checks: &[Check]) -> Status {
+    checks
+        .iter()
+        .map(|c| c.status)
+        .max()
+        .unwrap_or(Status::Pass)
+}
+
+pub fn print_report(path: &Path, spec: &ProfileSpec, checks: &[Check]) {
+    let count = |status| checks.iter().filter(|c| c.status == status).count();
+    println!(
+        "{}: {} {} ({} pass, {} warn, {} fail)",
+        path.display(),
+        spec.name,
+        overall(checks).as_str(),
+        count(Status::Pass),
+        count(Status::Warn),
+        count(Status::Fail)
+    );
+    for check in checks {
+        println!(
+            "  {}  {:<30} {}",
+            check.status.as_str(),
+            check.requirement,
+            check.detail
+        );
+    }
+}
+
+fn check_rule(
+    rule: Rule,
+    info: &Jp2Info,
+    cs: &CodestreamInfo,
+    spec: &ProfileSpec,
+) -> (bool, String) {
+    let yes_no = |b: bool| if b { "present" } else { "missing" };
+    let resolutions = cs.levels as u32 + 1;
+    match rule {
+        Rule::Lossless => (
+            cs.transform == 1 && cs.quantization == 0,
+            format!(
+                "{}, {}",
+                if cs.transform == 1 { "5/3" } else { "9/7" },
+                if cs.quantization == 0 {
+                    "no quantization"
+                } else {
+                    "quantized"
+                }
+            ),
+        ),
+        Rule::Layers => (
+            cs.layers == spec.layers,
+            format!("{} (expected {})", cs.layers, spec.layers),
+        ),
+        Rule::Order => {
+            let order = codestream::progression_name(cs.progression);
+            (
+                order == spec.order,
+                format!("{} (expected {})", order, spec.order),
+            )
+        }
+        Rule::Levels => (
+            (spec.min_levels..=spec.max_levels).contains(&cs.levels),
+            if spec.min_levels == spec.max_levels {
+                format!("{} (expected {})", cs.levels, spec.min_levels)
+            } else {
+                format!(
+                    "{} (expected {}-{})",
+                    cs.levels, spec.min_levels, spec.max_levels
+                )
+            },
+        ),
+        Rule::CodeBlock => (
+            cs.code_block == spec.block,
+            format!(
+                "{}x{} (expected {}x{})",
+                cs.code_block.0, cs.code_block.1, spec.block.0, spec.block.1
+            ),
+        ),
+        Rule::Tiles => {
+            // A single tile covering the whole image is as good as the nominal size
+            let single = cs.tile_count() == 1
+                && cs.tile_size.0 >= cs.width
+                && cs.tile_size.1 >= cs.height
+                && spec.tile.0 >= cs.width
+                && spec.tile.1 >= cs.height;
+            (
+                cs.tile_size == spec.tile || single,
+                format!(
+                    "{}x{}{} (expected {}x{})",
+                    cs.tile_size.0,
+                    cs.tile_size.1,
+                    if single { ", single tile" } else { "" },
+                    spec.tile.0,
+                    spec.tile.1
+                ),
+            )
+        }
+        Rule::Precincts => {
+            let expected = |layout: Precincts| -> Vec<(u32, u32)> {
+                layout
+                    .sizes(resolutions)
+                    .into_iter()
+                    .map(|size| (size.max(cs.code_block.0), size.max(cs.code_block.1)))
+                    .collect()
+            };
+            let layouts = std::iter::once(&spec.precincts).chain(spec.other_precincts);
+            let list = |p: &[(u32, u32)]| {
+                if p.is_empty() {
+                    "none".to_string()
+                } else {
+                    p.iter()
+                        .map(|(w, h)| format!("{}x{}", w, h))
+                        .collect::<Vec<_>>()
+                        .join(",")
+                }
+            };
+            let expected: Vec<Vec<(u32, u32)>> = layouts.map(|&layout| expected(layout)).collect();
+            (
+                expected.contains(&cs.precincts),
+                format!(
+                    "{} (expected {}, lowest resolution first)",
+                    list(&cs.precincts),
+                    expected
+                        .iter()
+                        .map(|p| list(p))
+                        .collect::<Vec<_>>()
+                        .join(" or ")
+                ),
+            )
+        }
+        Rule::Sop => (
+            cs.sop_signalled && cs.sop_found,
+            format!(
+                "signalled {}, {} in packets",
+                cs.sop_signalled,
+                yes_no(cs.sop_found)
+            ),
+        ),
+        Rule::Eph => (
+            cs.eph_signalled && cs.eph_found,
+            format!(
+                "signalled {}, {} in packets",
+                cs.eph_signalled,
+                yes_no(cs.eph_found)
+            ),
+        ),
+        Rule::TilePartsR => (
+            cs.tiles_seen > 0 && cs.tile_parts == cs.tiles_seen * resolutions,
+            format!(
+                "{} tile-part(s) for {} tile(s), expected {} per tile",
+                cs.tile_parts, cs.tiles_seen, resolutions
+            ),
+        ),
+        Rule::Tlm => (
+            cs.tlm_segments > 0,
+            format!("{} TLM segment(s)", cs.tlm_segments),
+        ),
+        Rule::Bypass => (
+            cs.code_block_style & 0x01 != 0,
+            format!("code-block style 0x{:02X}", cs.code_block_style),
+        ),
+        Rule::Mct => {
+            if cs.components.len() < 3 {
+                (true, "not applicable (fewer than 3 components)".to_string())
+            } else {
+                (cs.mct == 1, format!("MCT={}", cs.mct))
+            }
+        }
+        Rule::Icc => {
+            let methods = colr_methods(&info.boxes);
+            match methods.iter().find(|&&m| matches!(m, 2 | 3)) {
+                Some(method) => (true, format!("'colr' method {}", method)),
+                None if methods.is_empty() => (false, "no 'colr' box".to_string()),
+                None => (false, "enumerated colour space only, no ICC".to_string()),
+            }
+        }
+    }
+}
+
+/// Marker segments that can change what `rule` read from the main-header
+/// COD/QCD, e.g. a COC switching one component to 9/7 or a tile-part COD
+/// with another progression order.
+fn overrides(rule: Rule, cs: &CodestreamInfo) -> Option<String> {
+    let (coc, qcc, poc) = match rule {
+        Rule::Lossless => (true, true, false),
+        Rule::Levels | Rule::CodeBlock | Rule::Precincts | Rule::Bypass => (true, false, false),
+        Rule::Order => (false, false, true),
+        Rule::Layers | Rule::Sop | Rule::Eph | Rule::Mct => (false, false, false),
+        Rule::Tiles | Rule::TilePartsR | Rule::Tlm | Rule::Icc => return None,
+    };
+    let mut found = Vec::new();
+    if coc && cs.coc_segments > 0 {
+        found.push(format!("{} COC", cs.coc_segments));
+    }
+    if qcc && cs.qcc_segments > 0 {
+        found.push(format!("{} QCC", cs.qcc_segments));
+    }
+    if poc && cs.poc_segments > 0 {
+        found.push(format!("{} POC", cs.poc_segments));
+    }
+    if cs.tile_header_overrides > 0 {
+        found.push(format!(
+            "{} tile-part header(s) with COD/COC/QCD/QCC/POC",
+            cs.tile_header_overrides
+        ));
+    }
+    (!found.is_empty()).then(|| found.join(", "))
+}
+
+fn colr_methods(boxes: &[Jp2Box]) -> Vec<u8> {
+    let mut methods = Vec::new();
+    for jp2_box in boxes {
+        if let Some(BoxDetails::ColourSpec { method, .. }) = &jp2_box.details {
+            methods.push(*method);
+        }
+        methods.extend(colr_methods(&jp2_box.children));
+    }
+    methods
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 4096x4096 RGB file coded exactly as the NDK preset writes it.
+    fn ndk_master() -> Jp2Info {
+        let colr = Jp2Box {
+            offset: 45,
+            length: 15,
+            extended: false,
+            kind: *b"colr",
+            details: Some(BoxDetails::ColourSpec {
+                method: 2,
+                precedence: 0,
+                approx: 0,
+                enumcs: None,
+                icc_len: Some(4),
+            }),
+            children: Vec::new(),
+        };
+        let jp2h = Jp2Box {
+            offset: 32,
+            length: 28,
+            extended: false,
+            kind: *b"jp2h",
+            details: None,
+            children: vec![colr],
+        };
+        let cs = CodestreamInfo {
+            width: 4096,
+            height: 4096,
+            tile_size: (4096, 4096),
+            tiles: (1, 1),
+            components: vec![(8, false, 1, 1); 3],
+            progression: 2,
+            layers: 1,
+            mct: 1,
+            levels: 5,
+            code_block: (64, 64),
+            code_block_style: 0x01,
+            transform: 1,
+            precincts: precincts(Precincts::LowestTwo256),
+            sop_signalled: true,
+            eph_signalled: true,
+            sop_found: true,
+            eph_found: true,
+            tlm_segments: 1,
+            tile_parts: 6,
+            tile_parts_per_tile: 6,
+            tiles_seen: 1,
+            eoc: true,
+            ..Default::default()
+        };
+        Jp2Info {
+            width: 4096,
+            height: 4096,
+            components: Vec::new(),
+            icc_profile_len: 4,
+            layers: 1,
+            boxes: vec![jp2h],
+            codestream: Some(cs),
+        }
+    }
+
+    fn precincts(layout: Precincts) -> Vec<(u32, u32)> {
+        layout.sizes(6).into_iter().map(|s| (s, s)).collect()
+    }
+
+    fn codestream(info: &mut Jp2Info) -> &mut CodestreamInfo {
+        info.codestream.as_mut().unwrap()
+    }
+
+    fn status(checks: &[Check], rule: Rule) -> Status {
+        checks
+            .iter()
+            .find(|c| c.requirement == rule.label())
+            .unwrap()
+            .status
+    }
+
+    #[test]
+    fn preset_output_passes() {
+        let checks = validate(&ndk_master(), &NDK);
+        assert_eq!(checks.len(), NDK.rules.len());
+        for check in &checks {
+            assert_eq!(check.status, Status::Pass, "{}", check.requirement);
+        }
+    }
+
+    #[test]
+    fn irreversible_wavelet_fails() {
+        let mut info = ndk_master();
+        codestream(&mut info).transform = 0;
+        codestream(&mut info).quantization = 2;
+        let checks = validate(&info, &NDK);
+        assert_eq!(status(&checks, Rule::Lossless), Status::Fail);
+        assert_eq!(overall(&checks), Status::Fail);
+        assert_eq!(overall(&validate(&info, &FADGI)), Status::Fail);
+    }
+
+    #[test]
+    fn fadgi_recommendations_only_warn() {
+        let mut info = ndk_master();
+        codestream(&mut info).progression = 0;
+        codestream(&mut info).code_block = (32, 32);
+        assert_eq!(status(&validate(&info, &NDK), Rule::Order), Status::Fail);
+        let checks = validate(&info, &FADGI);
+        assert_eq!(status(&checks, Rule::Order), Status::Warn);
+        assert_eq!(status(&checks, Rule::CodeBlock), Status::Warn);
+        assert_eq!(overall(&checks), Status::Warn);
+    }
+
+    #[test]
+    fn single_tile_counts_as_nominal_size() {
+        let mut info = ndk_master();
+        let cs = codestream(&mut info);
+        (cs.width, cs.height) = (3000, 2000);
+        cs.tile_size = (3000, 2000);
+        assert_eq!(status(&validate(&info, &NDK), Rule::Tiles), Status::Pass);
+
+        // One tile larger than the nominal size is not
+        let cs = codestream(&mut info);
+        (cs.width, cs.height) = (5000, 5000);
+        cs.tile_size = (5000, 5000);
+        assert_eq!(status(&validate(&info, &NDK), Rule::Tiles), Status::Warn);
+        assert_eq!(
+            status(&validate(&info, &METAMORFOZE), Rule::Tiles),
+            Status::Warn
+        );
+    }
+
+    #[test]
+    fn overrides_fail_the_coding_rules() {
+        let mut info = ndk_master();
+        codestream(&mut info).coc_segments = 1;
+        let checks = validate(&info, &NDK);
+        assert_eq!(status(&checks, Rule::Lossless), Status::Fail);
+        assert_eq!(status(&checks, Rule::CodeBlock), Status::Fail);
+        assert_eq!(status(&checks, Rule::Order), Status::Pass);
+
+        let mut info = ndk_master();
+        codestream(&mut info).tile_header_overrides = 2;
+        let checks = validate(&info, &NDK);
+        assert_eq!(status(&checks, Rule::Order), Status::Fail);
+        assert_eq!(status(&checks, Rule::Tlm), Status::Pass);
+        assert_eq!(status(&checks, Rule::Icc), Status::Pass);
+    }
+
+    #[test]
+    fn either_precinct_layout_passes_ndk() {
+        let mut info = ndk_master();
+        codestream(&mut info).precincts = precincts(Precincts::HighestTwo256);
+        assert_eq!(
+            status(&validate(&info, &NDK), Rule::Precincts),
+            Status::Pass
+        );
+        codestream(&mut info).precincts = Vec::new();
+        assert_eq!(
+            status(&validate(&info, &NDK), Rule::Precincts),
+            Status::Fail
+        );
+
+        let info = ndk_master();
+        assert_eq!(
+            status(&validate(&info, &METAMORFOZE), Rule::Precincts),
+            Status::Fail
+        );
+    }
+
+    #[test]
+    fn missing_codestream_fails() {
+        let mut info = ndk_master();
+        info.codestream = None;
+        assert_eq!(overall(&validate(&info, &NDK)), Status::Fail);
+    }
+}
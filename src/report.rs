@@ -0,0 +1,267 @@
+//! Per-file outcomes of an encode/decode run: the final summary, the process
+//! exit code and the optional `--report` file (JSON or CSV).
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use anyhow::{Context, Result, bail};
+
+use crate::json::{JsonObject, csv_field, json_array, json_f64, json_string};
+
+/// Exit code when at least one file failed.
+pub const EXIT_FAILED: u8 = 3;
+/// Exit code when a conversion panicked or its worker crashed (takes precedence over failures).
+pub const EXIT_PANICKED: u8 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Ok,
+    /// Output exists and --force was not given.
+    Skipped,
+    Failed,
+    /// A panic, or an `--isolate` worker that crashed.
+    Panicked,
+}
+
+impl Outcome {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Ok => "ok",
+            Outcome::Skipped => "skipped",
+            Outcome::Failed => "failed",
+            Outcome::Panicked => "panicked",
+        }
+    }
+}
+
+/// One conversion target (a file, or one page with --all-pages).
+#[derive(Debug, Clone)]
+pub struct FileResult {
+    pub input: PathBuf,
+    /// 1-based TIFF page when the input has more than one page.
+    pub page: Option<usize>,
+    pub output: Option<PathBuf>,
+    pub outcome: Outcome,
+    pub seconds: f64,
+    pub input_bytes: Option<u64>,
+    pub output_bytes: Option<u64>,
+    /// Error or panic message.
+    pub message: Option<String>,
+}
+
+impl FileResult {
+    pub fn new(input: &Path, output: Option<&Path>, outcome: Outcome) -> Self {
+        Self {
+            input: input.to_path_buf(),
+            page: None,
+            output: output.map(Path::to_path_buf),
+            outcome,
+            seconds: 0.0,
+            input_bytes: fs::metadata(input).ok().map(|m| m.len()),
+            output_bytes: None,
+            message: None,
+        }
+    }
+
+    /// Marks the result as one page of a multi-page input. The input size is
+    /// then left out: the whole file's size would inflate the ratio.
+    pub fn for_page(mut self, page: Option<usize>) -> Self {
+        if page.is_some() {
+            self.page = page;
+            self.input_bytes = None;
+        }
+        self
+    }
+
+    /// Input file size divided by output file size.
+    pub fn ratio(&self) -> Option<f64> {
+        match (self.input_bytes, self.output_bytes) {
+            (Some(input), Some(output)) if output > 0 => Some(input as f64 / output as f64),
+            _ => None,
+        }
+    }
+}
+
+/// Output format of `--report`, taken from the file extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    Json,
+    Csv,
+}
+
+/// `--report PATH`: the extension must be .json or .csv.
+pub fn parse_report_path(s: &str) -> Result<PathBuf> {
+    let path = PathBuf::from(s);
+    report_format(&path)?;
+    Ok(path)
+}
+
+pub fn report_format(path: &Path) -> Result<ReportFormat> {
+    match path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_ascii_lowercase)
+        .as_deref()
+    {
+        Some("json") => Ok(ReportFormat::Json),
+        Some("csv") => Ok(ReportFormat::Csv),
+        _ => bail!("report file must end in .json or .csv"),
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RunSummary {
+    pub results: Vec<FileResult>,
+}
+
+impl RunSummary {
+    pub fn record(&mut self, result: FileResult) {
+        self.results.push(result);
+    }
+
+    pub fn count(&self, outcome: Outcome) -> usize {
+        self.results.iter().filter(|r| r.outcome == outcome).count()
+    }
+
+    /// 0 when nothing failed, else `EXIT_PANICKED` or `EXIT_FAILED`.
+    pub fn exit_code(&self) -> ExitCode {
+        if self.count(Outcome::Panicked) > 0 {
+            ExitCode::from(EXIT_PANICKED)
+        } else if self.count(Outcome::Failed) > 0 {
+            ExitCode::from(EXIT_FAILED)
+        } else {
+            ExitCode::SUCCESS
+        }
+    }
+
+    /// Counts line plus the failed paths, on stderr.
+    pub fn print(&self) {
+        let failed: Vec<&FileResult> = self
+            .results
+            .iter()
+            .filter(|r| matches!(r.outcome, Outcome::Failed | Outcome::Panicked))
+            .collect();
+        eprintln!(
+            "Summary: {} ok, {} skipped, {} failed, {} panicked ({} total)",
+            self.count(Outcome::Ok),
+            self.count(Outcome::Skipped),
+            self.count(Outcome::Failed),
+            self.count(Outcome::Panicked),
+            self.results.len()
+        );
+        if failed.is_empty() {
+            eprintln!("All files processed successfully!");
+            return;
+        }
+        eprintln!("Failed files:");
+        for result in failed {
+            eprintln!(
+                "  {} [{}] {}",
+                result.input.display(),
+                result.outcome.as_str(),
+                result.message.as_deref().unwrap_or("")
+            );
+        }
+    }
+
+    pub fn write_report(&self, path: &Path) -> Result<()> {
+        let text = match report_format(path)? {
+            ReportFormat::Json => self.to_json(),
+            ReportFormat::Csv => self.to_csv(),
+        };
+        fs::write(path, text).with_context(|| format!("Writing report {}", path.display()))
+    }
+
+    fn to_json(&self) -> String {
+        let opt_u64 = |v: Option<u64>| v.map_or("null".into(), |v| v.to_string());
+        let files = self.results.iter().map(|r| {
+            JsonObject::new()
+                .str("input", &r.input.display().to_string())
+                .raw("page", opt_u64(r.page.map(|p| p as u64)))
+                .raw(
+                    "output",
+                    r.output
+                        .as_ref()
+                        .map_or("null".into(), |p| json_string(&p.display().to_string())),
+                )
+                .str("status", r.outcome.as_str())
+                .raw("seconds", json_f64(r.seconds))
+                .raw("input_bytes", opt_u64(r.input_bytes))
+                .raw("output_bytes", opt_u64(r.output_bytes))
+                .raw("ratio", r.ratio().map_or("null".into(), json_f64))
+                .raw(
+                    "message",
+                    r.message.as_deref().map_or("null".into(), json_string),
+                )
+                .finish()
+        });
+        let summary = JsonObject::new()
+            .raw("total", self.results.len())
+            .raw("ok", self.count(Outcome::Ok))
+            .raw("skipped", self.count(Outcome::Skipped))
+            .raw("failed", self.count(Outcome::Failed))
+            .raw("panicked", self.count(Outcome::Panicked))
+            .finish();
+        let mut json = JsonObject::new()
+            .raw("summary", summary)
+            .raw("files", json_array(files))
+            .finish();
+        json.push('\n');
+        json
+    }
+
+    fn to_csv(&self) -> String {
+        let mut csv = String::from(
+            "input,page,output,status,seconds,input_bytes,output_bytes,ratio,message\n",
+        );
+        let opt = |v: Option<String>| v.unwrap_or_default();
+        for r in &self.results {
+            let fields = [
+                csv_field(&r.input.display().to_string()),
+                opt(r.page.map(|v| v.to_string())),
+                csv_field(&opt(r.output.as_ref().map(|p| p.display().to_string()))),
+                r.outcome.as_str().to_string(),
+                format!("{:.3}", r.seconds),
+                opt(r.input_bytes.map(|v| v.to_string())),
+                opt(r.output_bytes.map(|v| v.to_string())),
+                opt(r.ratio().map(|v| format!("{:.3}", v))),
+                csv_field(r.message.as_deref().unwrap_or("")),
+            ];
+            csv.push_str(&fields.join(","));
+            csv.push('\n');
+        }
+        csv
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn converted(page: Option<usize>) -> FileResult {
+        let mut result = FileResult::new(Path::new("scan.tif"), None, Outcome::Ok);
+        result.input_bytes = Some(3000);
+        result.output_bytes = Some(1000);
+        result.for_page(page)
+    }
+
+    #[test]
+    fn page_rows_have_no_input_size() {
+        let summary = RunSummary {
+            results: vec![converted(None), converted(Some(2))],
+        };
+        assert_eq!(summary.results[0].ratio(), Some(3.0));
+        assert_eq!(summary.results[1].ratio(), None);
+
+        let csv = summary.to_csv();
+        let rows: Vec<&str> = csv.lines().collect();
+        assert!(rows[0].starts_with("input,page,output,"));
+        assert_eq!(rows[1], "scan.tif,,,ok,0.000,3000,1000,3.000,");
+        assert_eq!(rows[2], "scan.tif,2,,ok,0.000,,1000,,");
+        let json = summary.to_json();
+        assert!(
+            json.contains(r#""page":2,"output":null,"status":"ok","seconds":0,"input_bytes":null"#)
+        );
+    }
+}
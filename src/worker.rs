@@ -0,0 +1,120 @@
+//! `--isolate`: every conversion runs in a child `tif2jp2` process, so a
+//! segfault or abort inside OpenJPEG fails one file instead of the batch.
+
+use std::ffi::OsString;
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::process::{Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, anyhow, bail};
+
+use crate::atomic;
+use crate::report::EXIT_PANICKED;
+
+/// A worker reports its error on a stderr line with this prefix; the parent
+/// keeps it as the file's message instead of forwarding it.
+pub const ERROR_PREFIX: &str = "@@tif2jp2-worker-error: ";
+
+/// How often a worker with a timeout is checked.
+const POLL: Duration = Duration::from_millis(20);
+
+/// The worker died from a signal or a panic rather than returning an error.
+#[derive(Debug, thiserror::Error)]
+#[error("worker crashed: {0}")]
+pub struct Crashed(pub String);
+
+/// Converts `input` (page `ifd`) into `out` in a child process started with
+/// `options` (see `Args::worker_args`) plus the hidden worker options.
+pub fn run(
+    options: &[OsString],
+    input: &Path,
+    ifd: usize,
+    out: &Path,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    let exe = std::env::current_exe().context("Locating the tif2jp2 executable")?;
+    let mut child = Command::new(exe)
+        .args(options)
+        .arg("--worker-ifd")
+        .arg(ifd.to_string())
+        .arg("--worker-output")
+        .arg(out)
+        .arg("--")
+        .arg(input)
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Starting worker process")?;
+
+    // Forward the worker's log, keeping its error line
+    let stderr = child.stderr.take();
+    let reader = thread::spawn(move || {
+        let mut message = None;
+        if let Some(stderr) = stderr {
+            for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                match line.strip_prefix(ERROR_PREFIX) {
+                    Some(error) => message = Some(error.to_string()),
+                    None => eprintln!("{}", line),
+                }
+            }
+        }
+        message
+    });
+
+    let status = match timeout {
+        None => child.wait()?,
+        Some(limit) => {
+            let started = Instant::now();
+            loop {
+                if let Some(status) = child.try_wait()? {
+                    break status;
+                }
+                if started.elapsed() >= limit {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    let _ = reader.join();
+                    // The worker never got to rename its incomplete output
+                    let _ = fs::remove_file(atomic::temp_path(out));
+                    bail!("timed out after {:.0?}, worker killed", limit);
+                }
+                thread::sleep(POLL);
+            }
+        }
+    };
+    let message = reader.join().unwrap_or(None);
+
+    match status.code() {
+        Some(0) => Ok(()),
+        Some(code) if code == EXIT_PANICKED as i32 => {
+            let _ = fs::remove_file(atomic::temp_path(out));
+            Err(Crashed(message.unwrap_or_else(|| "panic".to_string())).into())
+        }
+        Some(code) => Err(anyhow!(
+            message.unwrap_or_else(|| format!("worker exited with code {}", code))
+        )),
+        None => {
+            let _ = fs::remove_file(atomic::temp_path(out));
+            Err(Crashed(describe_signal(status)).into())
+        }
+    }
+}
+
+#[cfg(unix)]
+fn describe_signal(status: ExitStatus) -> String {
+    use std::os::unix::process::ExitStatusExt;
+    match status.signal() {
+        Some(libc::SIGSEGV) => "segmentation fault (SIGSEGV)".to_string(),
+        Some(libc::SIGABRT) => "aborted (SIGABRT)".to_string(),
+        Some(libc::SIGBUS) => "bus error (SIGBUS)".to_string(),
+        Some(libc::SIGKILL) => "killed (SIGKILL)".to_string(),
+        Some(signal) => format!("signal {}", signal),
+        None => "terminated abnormally".to_string(),
+    }
+}
+
+#[cfg(not(unix))]
+fn describe_signal(_status: ExitStatus) -> String {
+    "terminated abnormally".to_string()
+}
@@ -0,0 +1,131 @@
+//! Minimal XMP reading: single property values from an XMP packet, without an
+//! XML parser. Both the attribute form (`tiff:XResolution="300/1"`) and the
+//! element form (`<tiff:XResolution>300/1</tiff:XResolution>`) are recognised.
+
+/// Value of the simple property `name` (e.g. `tiff:XResolution`).
+pub fn property<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    // Attribute form; the preceding whitespace keeps `xyz:name` from matching
+    let mut from = 0;
+    while let Some(found) = xml[from..].find(name) {
+        let start = from + found;
+        let rest = &xml[start + name.len()..];
+        let preceded_ok = xml[..start]
+            .chars()
+            .next_back()
+            .is_some_and(|c| c.is_whitespace() || c == '<');
+        if preceded_ok {
+            if let Some(value) = attribute_value(rest) {
+                return Some(value);
+            }
+            if let Some(inner) = rest.strip_prefix('>') {
+                let end = inner.find('<')?;
+                return Some(inner[..end].trim());
+            }
+        }
+        from = start + name.len();
+    }
+    None
+}
+
+/// `="value"` or `='value'` at the start of `rest`, with optional spaces around `=`.
+fn attribute_value(rest: &str) -> Option<&str> {
+    let rest = rest.trim_start().strip_prefix('=')?.trim_start();
+    let quote = rest.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let inner = &rest[1..];
+    let end = inner.find(quote)?;
+    Some(&inner[..end])
+}
+
+/// Parses an XMP rational (`"300/1"`) or a plain decimal (`"300"`).
+pub fn parse_rational(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let parsed = match value.split_once('/') {
+        Some((n, d)) => {
+            let d: f64 = d.trim().parse().ok()?;
+            if d == 0.0 {
+                return None;
+            }
+            n.trim().parse::<f64>().ok()? / d
+        }
+        None => value.parse().ok()?,
+    };
+    parsed.is_finite().then_some(parsed)
+}
+
+/// Horizontal and vertical resolution in DPI from the TIFF properties of an
+/// XMP packet. ResolutionUnit 3 (centimetre) is converted; unit 1 (none)
+/// carries no physical size and yields `None`.
+pub fn resolution_dpi(xml: &str) -> Option<(f64, f64)> {
+    let x = parse_rational(property(xml, "tiff:XResolution")?)?;
+    let y = parse_rational(property(xml, "tiff:YResolution")?)?;
+    let per_inch = match property(xml, "tiff:ResolutionUnit").map(str::trim) {
+        None | Some("2") => 1.0,
+        Some("3") => 2.54,
+        Some(_) => return None,
+    };
+    (x > 0.0 && y > 0.0).then_some((x * per_inch, y * per_inch))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ATTRIBUTES: &str = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF>
+ <rdf:Description crs:tiff:XResolution="1/1"
+  tiff:XResolution="300/1" tiff:YResolution = '600'
+  tiff:ResolutionUnit="2"/>
+</rdf:RDF></x:xmpmeta>"#;
+
+    const ELEMENTS: &str = r#"<rdf:Description>
+ <tiff:XResolution> 1181/10 </tiff:XResolution>
+ <tiff:YResolution>118.1</tiff:YResolution>
+ <tiff:ResolutionUnit>3</tiff:ResolutionUnit>
+</rdf:Description>"#;
+
+    #[test]
+    fn attribute_and_element_forms() {
+        assert_eq!(property(ATTRIBUTES, "tiff:XResolution"), Some("300/1"));
+        assert_eq!(property(ATTRIBUTES, "tiff:YResolution"), Some("600"));
+        assert_eq!(property(ELEMENTS, "tiff:XResolution"), Some("1181/10"));
+        assert_eq!(property(ELEMENTS, "tiff:Missing"), None);
+    }
+
+    #[test]
+    fn resolution_units() {
+        assert_eq!(resolution_dpi(ATTRIBUTES), Some((300.0, 600.0)));
+        let (x, y) = resolution_dpi(ELEMENTS).unwrap();
+        assert!((x - 299.974).abs() < 1e-9 && (y - 299.974).abs() < 1e-9);
+
+        let no_unit = ATTRIBUTES.replace(r#"tiff:ResolutionUnit="2""#, "");
+        assert_eq!(resolution_dpi(&no_unit), Some((300.0, 600.0)));
+        let unitless = ATTRIBUTES.replace(r#"ResolutionUnit="2""#, r#"ResolutionUnit="1""#);
+        assert_eq!(resolution_dpi(&unitless), None);
+        let zero = ATTRIBUTES.replace("300/1", "0/1");
+        assert_eq!(resolution_dpi(&zero), None);
+    }
+
+    #[test]
+    fn rationals() {
+        assert_eq!(parse_rational(" 72/1 "), Some(72.0));
+        assert_eq!(parse_rational("72.5"), Some(72.5));
+        assert_eq!(parse_rational("1/0"), None);
+        assert_eq!(parse_rational("inf"), None);
+        assert_eq!(parse_rational("1e400/1"), None);
+        assert_eq!(parse_rational("a/b"), None);
+        assert_eq!(parse_rational(""), None);
+    }
+
+    #[test]
+    fn truncated_packets_do_not_panic() {
+        for packet in [ATTRIBUTES, ELEMENTS] {
+            for (cut, _) in packet.char_indices() {
+                let _ = resolution_dpi(&packet[..cut]);
+            }
+        }
+        assert_eq!(property("<tiff:XResolution>300", "tiff:XResolution"), None);
+        assert_eq!(
+            property(r#" tiff:XResolution="300"#, "tiff:XResolution"),
+            None
+        );
+    }
+}